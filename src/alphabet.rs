@@ -0,0 +1,75 @@
+use crate::{err::Error, Result};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+const PRIVATE_USE: RangeInclusive<char> = '\u{e000}'..='\u{f8ff}';
+
+/// Characters the dictionary codes are made of, recorded in the archive header.
+///
+/// Code characters are always split as alphabetic, so a code stays a token
+/// of its own wherever it is put.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Alphabet {
+    /// ASCII letters, 52 single byte codes.
+    Letters,
+    /// ASCII letters, then the Unicode private use area `U+E000..=U+F8FF`
+    /// for 6400 more codes of 3 bytes.
+    PrivateUse,
+}
+
+impl Alphabet {
+    #[inline]
+    pub fn from(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Self::Letters),
+            1 => Ok(Self::PrivateUse),
+            _ => Err(Error::corrupted("Unknown alphabet.")),
+        }
+    }
+
+    #[inline]
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    #[inline]
+    pub fn chars(self) -> Vec<char> {
+        match self {
+            Self::Letters => ('A'..='Z').chain('a'..='z').collect(),
+            Self::PrivateUse => Self::Letters.chars().into_iter().chain(PRIVATE_USE).collect(),
+        }
+    }
+
+    #[inline]
+    pub fn contains(self, c: char) -> bool {
+        match self {
+            Self::Letters => c.is_ascii_alphabetic(),
+            Self::PrivateUse => Self::Letters.contains(c) || PRIVATE_USE.contains(&c),
+        }
+    }
+}
+
+impl FromStr for Alphabet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "letters" => Ok(Self::Letters),
+            "private" => Ok(Self::PrivateUse),
+            _ => Err(Error::invalid_args("Expected letters or private alphabet!")),
+        }
+    }
+}
+
+#[test]
+fn alphabet_cases() {
+    for a in [Alphabet::Letters, Alphabet::PrivateUse] {
+        assert_eq!(Alphabet::from(a.id()).unwrap(), a);
+        assert!(a.chars().into_iter().all(|c| a.contains(c)));
+    }
+    assert!(Alphabet::from(2).is_err());
+
+    assert_eq!(Alphabet::Letters.chars().len(), 52);
+    assert_eq!(Alphabet::PrivateUse.chars().len(), 52 + 6400);
+    assert!(!Alphabet::Letters.contains('\u{e000}'));
+}
@@ -2,22 +2,47 @@ use args::{Args as MArgs, ArgsError};
 use getopts::Occur;
 use std::{path::PathBuf as Path, process};
 
-const PROGRAM_NAME: &'static str = "wordzip";
-const PROGRAM_DESC: &'static str = "Usage: wordzip [mode(-c/-d)] -i [input-file] -o [output-file]";
+const PROGRAM_NAME: &str = "wordzip";
+const PROGRAM_DESC: &str = "Usage: wordzip [mode(-c/-d)] -i [input-file] -o [output-file]\n\
+                             \x20      wordzip train -i [sample] -o [dictionary]\n\n\
+                             Without a path or with `-` the standard input/output is used.\n\
+                             Exit codes: 1 io, 2 arguments, 3 output exists, 4 empty input,\n\
+                             5 corrupted, 6 unsupported version, 7 checksum, 8 data mismatch.";
+const BLOCK_SIZE: usize = 1024; // KiB
+const MAX_NGRAM: usize = 3; // words
 
-use crate::Result;
+use crate::{
+    alphabet::Alphabet,
+    checksum::Checksum,
+    proc::{check_block_size, Method},
+    Error, Result,
+};
 
 #[derive(Debug, PartialEq)]
 pub struct Args {
     pub mode: Mode,
-    pub input_file: Path,
-    pub output_file: Path,
+    pub input_file: Option<Path>, // `None` for stdin
+    pub output_file: Option<Path>, // `None` for stdout
+    pub force: bool,
+    pub block_size: usize,
+    pub checksum: Checksum,
+    pub huffman: bool,
+    pub alphabet: Alphabet,
+    pub max_ngram: usize,
+    pub threads: usize,
+    pub method: Method,
+    pub lz77: bool,
+    pub fold_case: bool,
+    pub gaps: bool,
+    pub dict: Option<Path>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Mode {
     Zip,
     Unzip,
+    /// Makes a shared dictionary out of the input.
+    Train,
 }
 
 impl Args {
@@ -32,7 +57,11 @@ impl Args {
         args.flag("c", "compress", "Zipress the given file");
         args.flag("d", "decompress", "Unzipress the given file");
 
-        args.flag("f", "force", "Override output file if it's exists");
+        args.flag(
+            "f",
+            "force",
+            "Override output file if it's exists, or write compressed data to a terminal",
+        );
 
         args.option(
             "i",
@@ -50,6 +79,83 @@ impl Args {
             Occur::Optional,
             None,
         );
+        args.option(
+            "b",
+            "block-size",
+            "Compresses the input in blocks of this size, bounding memory use",
+            "<KiB>",
+            Occur::Optional,
+            Some(BLOCK_SIZE.to_string()),
+        );
+        args.option(
+            "k",
+            "checksum",
+            "Integrity check of every block: none, crc32, xxh64 or sha256",
+            "<kind>",
+            Occur::Optional,
+            Some("crc32".to_string()),
+        );
+
+        args.option(
+            "D",
+            "dict",
+            "Shared dictionary made by `wordzip train`, with the same -C and -g as for compressing",
+            "<path>",
+            Occur::Optional,
+            None,
+        );
+        args.option(
+            "a",
+            "alphabet",
+            "Characters of the dictionary codes: letters or private (Unicode private use)",
+            "<kind>",
+            Occur::Optional,
+            Some("letters".to_string()),
+        );
+        args.option(
+            "m",
+            "method",
+            "Coding of the tokens: words, bpe (pair merging), ppm (best ratio, slow) or none",
+            "<kind>",
+            Occur::Optional,
+            Some("words".to_string()),
+        );
+        args.option(
+            "n",
+            "ngram",
+            "Longest phrase in words that can be swapped for a single code, 1 for words only",
+            "<words>",
+            Occur::Optional,
+            Some(MAX_NGRAM.to_string()),
+        );
+        args.option(
+            "T",
+            "threads",
+            "Blocks compressed or decompressed at once, 0 for one per core",
+            "<n>",
+            Occur::Optional,
+            Some("0".to_string()),
+        );
+        args.flag(
+            "C",
+            "fold-case",
+            "Code capitalized words by their lowercase form and a marker",
+        );
+        args.flag(
+            "g",
+            "gaps",
+            "Code frequent runs of separators between words by a single byte",
+        );
+        args.flag(
+            "z",
+            "lz77",
+            "Replace repeated byte runs by back-references, after the method",
+        );
+        args.flag(
+            "H",
+            "huffman",
+            "Entropy-code every block with canonical Huffman codes",
+        );
 
         args.parse(input)?;
 
@@ -58,12 +164,15 @@ impl Args {
             process::exit(0);
         }
 
+        // The only free argument is the first one
+        let train = input.get(1).is_some_and(|a| a.to_string() == "train");
+
         let mut mode = Mode::Zip;
-        if args.value_of("decompress")? && args.value_of("compress")? {
-            return Err(Box::new(ArgsError::new(
-                "operation",
-                "Only one mode can be selected!",
-            )));
+        let modes = [train, args.value_of("decompress")?, args.value_of("compress")?];
+        if modes.iter().filter(|&&m| m).count() > 1 {
+            return Err(Error::invalid_args("Only one mode can be selected!"));
+        } else if train {
+            mode = Mode::Train
         } else if args.value_of("compress")? {
             mode = Mode::Zip
         } else if args.value_of("decompress")? {
@@ -72,74 +181,166 @@ impl Args {
 
         let input_file = args.value_of("input-file");
         let output_file = args.value_of("output-file");
+        let force = args.value_of("force")?;
+
+        let method = args.value_of("method")?;
+        let dict = Self::dict_parse(args.value_of("dict").ok())?;
+        if dict.is_some() && mode == Mode::Zip && method != Method::Words {
+            return Err(Error::invalid_args("A dictionary only works with the words method!"));
+        }
 
         Ok(Args {
             mode,
-            input_file: Path::from(Self::if_path_parse(input_file.into())?),
-            output_file: Path::from(Self::of_path_parse(
-                output_file.into(),
-                args.value_of("force")?,
-            )?),
+            input_file: Self::if_path_parse(input_file.into())?,
+            output_file: Self::of_path_parse(output_file.into(), force)?,
+            force,
+            block_size: Self::block_size_parse(args.value_of("block-size")?)?,
+            checksum: args.value_of("checksum")?,
+            huffman: args.value_of("huffman")?,
+            alphabet: args.value_of("alphabet")?,
+            max_ngram: Self::ngram_parse(args.value_of("ngram")?)?,
+            threads: args.value_of("threads")?,
+            method,
+            lz77: args.value_of("lz77")?,
+            fold_case: args.value_of("fold-case")?,
+            gaps: args.value_of("gaps")?,
+            dict,
         })
     }
 
     #[inline]
-    fn if_path_parse(s: Option<std::result::Result<String, ArgsError>>) -> Result<Path> {
-        if let Some(p) = s {
-            if let Ok(p) = p {
-                let path = Path::from(p.to_string());
+    fn if_path_parse(s: Option<std::result::Result<String, ArgsError>>) -> Result<Option<Path>> {
+        match s {
+            Some(Ok(p)) if p != "-" => {
+                let path = Path::from(p);
                 if path.is_file() {
-                    return Ok(path);
+                    Ok(Some(path))
+                } else {
+                    Err(Error::invalid_args("Specify correct file to compress!"))
                 }
             }
+            _ => Ok(None),
         }
-        Err(Box::new(ArgsError::new(
-            "path",
-            "Specify correct file to compress!",
-        )))
     }
 
     #[inline]
     fn of_path_parse(
         s: Option<std::result::Result<String, ArgsError>>,
         r#override: bool,
-    ) -> Result<Path> {
-        let mut path = Path::new();
-        if let Some(p) = s {
-            if let Ok(p) = p {
-                path = Path::from(p.to_string());
+    ) -> Result<Option<Path>> {
+        match s {
+            Some(Ok(p)) if p != "-" => {
+                let path = Path::from(p);
                 if (path.is_file() || path.is_dir()) && !r#override {
-                    return Err(Box::new(ArgsError::new(
-                        "path",
-                        "File with same name already exists!\n\
-                                Specify other file or use `-f` for override existing file.",
-                    )));
+                    return Err(Error::OutputExists(path.display().to_string()));
                 }
+                Ok(Some(path))
             }
+            _ => Ok(None),
+        }
+    }
+
+    #[inline]
+    fn dict_parse(s: Option<String>) -> Result<Option<Path>> {
+        match s.map(Path::from) {
+            Some(p) if !p.is_file() => Err(Error::invalid_args("Specify correct dictionary file!")),
+            p => Ok(p),
         }
-        Ok(path)
     }
 
     #[inline]
-    pub fn input_file(&self) -> &Path {
-        &self.input_file
+    fn block_size_parse(kib: usize) -> Result<usize> {
+        check_block_size(kib.saturating_mul(1024))
     }
 
     #[inline]
-    pub fn output_file(&self) -> &Path {
-        &self.output_file
+    fn ngram_parse(words: usize) -> Result<usize> {
+        match words {
+            1..=16 => Ok(words),
+            _ => Err(Error::invalid_args("Phrases must be between 1 and 16 words long!")),
+        }
+    }
+
+    #[inline]
+    pub fn input_file(&self) -> Option<&Path> {
+        self.input_file.as_ref()
+    }
+
+    #[inline]
+    pub fn output_file(&self) -> Option<&Path> {
+        self.output_file.as_ref()
+    }
+
+    #[inline]
+    pub fn force(&self) -> bool {
+        self.force
     }
 
     #[inline]
     pub fn mode(&self) -> &Mode {
         &self.mode
     }
+
+    #[inline]
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    #[inline]
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    #[inline]
+    pub fn huffman(&self) -> bool {
+        self.huffman
+    }
+
+    #[inline]
+    pub fn alphabet(&self) -> Alphabet {
+        self.alphabet
+    }
+
+    #[inline]
+    pub fn max_ngram(&self) -> usize {
+        self.max_ngram
+    }
+
+    #[inline]
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    #[inline]
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    #[inline]
+    pub fn lz77(&self) -> bool {
+        self.lz77
+    }
+
+    #[inline]
+    pub fn fold_case(&self) -> bool {
+        self.fold_case
+    }
+
+    #[inline]
+    pub fn gaps(&self) -> bool {
+        self.gaps
+    }
+
+    #[inline]
+    pub fn dict(&self) -> Option<&Path> {
+        self.dict.as_ref()
+    }
 }
 
 #[test]
 fn parse_cases() {
-    const OK: &'static str = "src/tests/ok.txt";
-    const PERMISSION_DENIED: &'static str = "src/tests/permission_denied.txt";
+    const OK: &str = "src/tests/ok.txt";
+    const PERMISSION_DENIED: &str = "src/tests/permission_denied.txt";
 
     let ok = vec!["-i", OK];
     let if_not_exists = vec!["-i", "asldfasdhfjklashfljkas.adsa"];
@@ -150,27 +351,75 @@ fn parse_cases() {
     let of_exits_override = vec!["-i", OK, "-o", PERMISSION_DENIED, "-f"];
 
     let two_modes_together = vec!["-c", "-d"];
+    let stdio = vec!["-c", "-i", "-", "-o", "-"];
+    let zero_block_size = vec!["-i", OK, "-b", "0"];
+    let huge_block_size = vec!["-i", OK, "-b", "4194304"];
+    let unknown_checksum = vec!["-i", OK, "-k", "md5"];
+    let unknown_alphabet = vec!["-i", OK, "-a", "greek"];
+    let zero_ngram = vec!["-i", OK, "-n", "0"];
+    let unknown_method = vec!["-i", OK, "-m", "lzw"];
+    let negative_threads = vec!["-i", OK, "-T", "-1"];
+    let missing_dict = vec!["-i", OK, "-D", "asdfhasdhfljkasfhj.dict"];
+    let dict_with_bpe = vec!["-i", OK, "-D", OK, "-m", "bpe"];
+    let train_and_unzip = vec!["wordzip", "train", "-d", "-i", OK];
 
     // Ok
     assert!(Args::parse(&ok).is_ok());
     assert!(Args::parse(&permission_denied).is_ok());
     assert!(Args::parse(&of_not_exits).is_ok());
     assert!(Args::parse(&of_exits_override).is_ok());
+    assert!(Args::parse(&stdio).is_ok());
 
     // Err
-    assert!(Args::parse(&if_not_exists).is_err());
-    assert!(Args::parse(&of_exits).is_err());
+    assert!(matches!(Args::parse(&if_not_exists), Err(Error::InvalidArgs(_))));
+    assert!(matches!(Args::parse(&of_exits), Err(Error::OutputExists(_))));
     assert!(Args::parse(&two_modes_together).is_err());
+    assert!(Args::parse(&zero_block_size).is_err());
+    assert!(Args::parse(&huge_block_size).is_err());
+    assert!(Args::parse(&unknown_checksum).is_err());
+    assert!(Args::parse(&unknown_alphabet).is_err());
+    assert!(Args::parse(&zero_ngram).is_err());
+    assert!(Args::parse(&unknown_method).is_err());
+    assert!(Args::parse(&negative_threads).is_err());
+    assert!(Args::parse(&missing_dict).is_err());
+    assert!(Args::parse(&dict_with_bpe).is_err());
+    assert!(Args::parse(&train_and_unzip).is_err());
 
     // General test
-    let good_args = vec!["-d", "-i", OK, "-o", "new.txt"];
+    let good_args = vec![
+        "-d", "-i", OK, "-o", "new.txt", "-b", "64", "-k", "xxh64", "-H", "-a", "private",
+        "-n", "5", "-m", "bpe", "-z", "-C", "-g", "-T", "4",
+    ];
 
     assert_eq!(
         Args::parse(&good_args).unwrap(),
         Args {
             mode: Mode::Unzip,
-            input_file: Path::from(OK),
-            output_file: Path::from("new.txt")
+            input_file: Some(Path::from(OK)),
+            output_file: Some(Path::from("new.txt")),
+            force: false,
+            block_size: 64 * 1024,
+            checksum: Checksum::Xxh64,
+            huffman: true,
+            alphabet: Alphabet::PrivateUse,
+            max_ngram: 5,
+            threads: 4,
+            method: Method::Bpe,
+            lz77: true,
+            fold_case: true,
+            gaps: true,
+            dict: None,
         }
     );
+
+    let train = vec!["wordzip", "train", "-i", OK, "-o", "new.dict", "-n", "2"];
+    let args = Args::parse(&train).unwrap();
+    assert_eq!((args.mode, args.max_ngram), (Mode::Train, 2));
+    let args = Args::parse(&vec!["-d", "-i", OK, "-D", OK]).unwrap();
+    assert_eq!(args.dict, Some(Path::from(OK)));
+
+    let pipe = vec!["-d", "-f"];
+    let args = Args::parse(&pipe).unwrap();
+    assert_eq!((args.input_file, args.output_file, args.force), (None, None, true));
+    assert_eq!(Args::parse(&stdio).unwrap().output_file, None);
 }
@@ -0,0 +1,257 @@
+//! Byte pair encoding of tokens.
+//!
+//! Every distinct token is a symbol, then the most frequent pair of adjacent
+//! symbols becomes a new one, again and again. The table is stored as
+//! `tokens (varint) | (length (varint) | token)* | pairs (varint) |
+//! (left (varint) | right (varint))*` and the data as the amount of symbols
+//! followed by every symbol as a varint.
+
+use crate::{
+    err::Error,
+    format::{push_varint, Reader},
+    Result,
+};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+/// Ids up to this fit into two varint bytes.
+const MAX_SYMBOLS: usize = 1 << 14;
+/// A pair used less often does not pay for its entry in the table.
+const MIN_COUNT: usize = 4;
+
+const NONE: usize = usize::MAX;
+
+#[derive(Debug, PartialEq)]
+pub struct Bpe {
+    tokens: Vec<Vec<u8>>,
+    pairs: Vec<(u32, u32)>,
+}
+
+impl Bpe {
+    /// Learns the merges for `s` and returns them with the symbols of `s`.
+    #[inline]
+    pub fn from(s: &[&[u8]]) -> (Self, Vec<u32>) {
+        // Frequent tokens get the small ids, ties in the order of appearance
+        let mut counts: HashMap<&[u8], (usize, usize)> = HashMap::new();
+        for (i, t) in s.iter().enumerate() {
+            counts.entry(t).or_insert((0, i)).0 += 1;
+        }
+        let mut tokens = counts.into_iter().collect::<Vec<_>>();
+        tokens.sort_by_key(|(_, (n, first))| (Reverse(*n), *first));
+
+        let ids: HashMap<&[u8], u32> =
+            tokens.iter().enumerate().map(|(id, (t, _))| (*t, id as u32)).collect();
+        let mut seq = s.iter().map(|t| ids[t]).collect::<Vec<_>>();
+
+        let mut bpe = Bpe {
+            tokens: tokens.into_iter().map(|(t, _)| t.to_vec()).collect(),
+            pairs: Vec::new(),
+        };
+        bpe.merge(&mut seq);
+        (bpe, seq)
+    }
+
+    /// Merges the most frequent pairs of `seq` in place.
+    ///
+    /// The symbols are a linked list, so that every merge only touches the
+    /// places of the pair and the counts of its neighbours.
+    #[inline]
+    fn merge(&mut self, seq: &mut Vec<u32>) {
+        let n = seq.len();
+        let mut next = (1..=n).collect::<Vec<_>>();
+        let mut prev = (0..n).map(|i| i.checked_sub(1).unwrap_or(NONE)).collect::<Vec<_>>();
+        let mut alive = vec![true; n];
+
+        let mut counts: HashMap<(u32, u32), usize> = HashMap::new();
+        let mut places: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+        for i in 1..n {
+            let p = (seq[i - 1], seq[i]);
+            *counts.entry(p).or_default() += 1;
+            places.entry(p).or_default().push(i - 1);
+        }
+
+        // Stale entries are skipped when their count is no longer current
+        let mut heap = counts.iter().map(|(&p, &c)| (c, Reverse(p))).collect::<BinaryHeap<_>>();
+
+        while self.tokens.len() + self.pairs.len() < MAX_SYMBOLS {
+            let Some((c, Reverse(p))) = heap.pop() else { break };
+            if counts.get(&p) != Some(&c) {
+                continue;
+            }
+            if c < MIN_COUNT {
+                break;
+            }
+
+            let id = (self.tokens.len() + self.pairs.len()) as u32;
+            self.pairs.push(p);
+
+            let mut changed = Vec::new();
+            for i in places.remove(&p).unwrap_or_default() {
+                let j = next[i];
+                if !alive[i] || seq[i] != p.0 || j >= n || seq[j] != p.1 {
+                    continue;
+                }
+                let (before, after) = (prev[i], next[j]);
+
+                let mut add = |q: (u32, u32), by: isize, at: usize| {
+                    let c = counts.entry(q).or_default();
+                    *c = c.saturating_add_signed(by);
+                    if by > 0 {
+                        places.entry(q).or_default().push(at);
+                    }
+                    changed.push(q);
+                };
+                add(p, -1, i);
+                if before != NONE {
+                    add((seq[before], p.0), -1, before);
+                    add((seq[before], id), 1, before);
+                }
+                if after < n {
+                    add((p.1, seq[after]), -1, j);
+                    add((id, seq[after]), 1, i);
+                    prev[after] = i;
+                }
+
+                seq[i] = id;
+                alive[j] = false;
+                next[i] = after;
+            }
+
+            counts.remove(&p);
+            for q in changed {
+                if let Some(&c) = counts.get(&q) {
+                    heap.push((c, Reverse(q)));
+                }
+            }
+        }
+
+        let mut i = 0;
+        seq.retain(|_| {
+            i += 1;
+            alive[i - 1]
+        });
+    }
+
+    #[inline]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+
+        push_varint(&mut b, self.tokens.len() as u64);
+        for t in &self.tokens {
+            push_varint(&mut b, t.len() as u64);
+            b.extend_from_slice(t);
+        }
+        push_varint(&mut b, self.pairs.len() as u64);
+        for &(l, r) in &self.pairs {
+            push_varint(&mut b, l as u64);
+            push_varint(&mut b, r as u64);
+        }
+        b
+    }
+
+    #[inline]
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        let corrupted = || Error::corrupted("Malformed merge table.");
+
+        let mut r = Reader::from(b);
+        let mut bpe = Bpe {
+            tokens: Vec::new(),
+            pairs: Vec::new(),
+        };
+
+        for _ in 0..r.varint()? {
+            let n = r.varint()? as usize;
+            let t = r.bytes(n)?;
+            if t.is_empty() {
+                return Err(corrupted());
+            }
+            bpe.tokens.push(t);
+        }
+        for _ in 0..r.varint()? {
+            let (l, r) = (r.varint()?, r.varint()?);
+            // Only earlier symbols, so expanding one always ends
+            let id = (bpe.tokens.len() + bpe.pairs.len()) as u64;
+            if l >= id || r >= id {
+                return Err(corrupted());
+            }
+            bpe.pairs.push((l as u32, r as u32));
+        }
+
+        if !r.at_end()? {
+            return Err(corrupted());
+        }
+        Ok(bpe)
+    }
+
+    #[inline]
+    pub fn encode(seq: &[u32]) -> Vec<u8> {
+        let mut b = Vec::with_capacity(seq.len() * 2);
+        push_varint(&mut b, seq.len() as u64);
+        seq.iter().for_each(|&s| push_varint(&mut b, s as u64));
+        b
+    }
+
+    /// Expands the symbols of `b`, which must not come to more than `size` bytes.
+    #[inline]
+    pub fn decode(&self, b: &[u8], size: usize) -> Result<Vec<u8>> {
+        let corrupted = || Error::corrupted("Malformed symbols.");
+
+        let mut r = Reader::from(b);
+        let mut out = Vec::new();
+        let mut stack = Vec::new();
+
+        for _ in 0..r.varint()? {
+            stack.push(r.varint()? as usize);
+
+            while let Some(s) = stack.pop() {
+                if let Some(t) = self.tokens.get(s) {
+                    out.extend_from_slice(t);
+                    if out.len() > size {
+                        return Err(corrupted());
+                    }
+                } else {
+                    let &(l, r) = self.pairs.get(s - self.tokens.len()).ok_or_else(corrupted)?;
+                    stack.push(r as usize);
+                    stack.push(l as usize);
+                }
+            }
+        }
+
+        if !r.at_end()? {
+            return Err(corrupted());
+        }
+        Ok(out)
+    }
+}
+
+#[test]
+fn bpe_cases() {
+    let text = std::fs::read("short").unwrap();
+    let tokens = text.split_inclusive(|&b| b == b' ').collect::<Vec<_>>();
+
+    let (bpe, seq) = Bpe::from(&tokens);
+    assert!(!bpe.pairs.is_empty());
+    assert!(seq.len() < tokens.len());
+
+    let table = bpe.to_bytes();
+    let back = Bpe::from_bytes(&table).unwrap();
+    assert_eq!(back, bpe);
+    assert_eq!(back.decode(&Bpe::encode(&seq), text.len()).unwrap(), text);
+    // The size only bounds the data
+    assert_eq!(back.decode(&Bpe::encode(&seq), usize::MAX).unwrap(), text);
+
+    // Runs of one token merge without overlapping
+    let run = vec![&b"a"[..]; 9];
+    let (bpe, seq) = Bpe::from(&run);
+    assert_eq!(bpe.decode(&Bpe::encode(&seq), 9).unwrap(), b"aaaaaaaaa");
+    let (bpe, seq) = Bpe::from(&[]);
+    assert_eq!(bpe.decode(&Bpe::encode(&seq), 0).unwrap(), b"");
+
+    // Forward references, unknown symbols and too much data
+    assert!(Bpe::from_bytes(&[1, 1, b'a', 1, 0, 1]).is_err());
+    let (bpe, seq) = Bpe::from(&tokens);
+    let data = Bpe::encode(&seq);
+    assert!(bpe.decode(&data, text.len() - 1).is_err());
+    assert!(bpe.decode(&Bpe::encode(&[u16::MAX as u32]), text.len()).is_err());
+    assert!(bpe.decode(&data[..data.len() - 1], text.len()).is_err());
+}
@@ -0,0 +1,118 @@
+//! Case folding of words.
+//!
+//! A title case or all caps word is lowercased and preceded by `TITLE` or
+//! `UPPER`, so its variants share one dictionary entry. Words of any other
+//! case are kept as they are, and a literal marker byte is doubled.
+
+use crate::{
+    alphabet::Alphabet,
+    err::Error,
+    proc::{is_word, split},
+    Result,
+};
+
+const TITLE: u8 = 0x1c;
+const UPPER: u8 = 0x1d;
+
+#[inline]
+fn title(w: &str) -> String {
+    let mut chars = w.chars();
+    chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default()
+}
+
+/// The marker and lowercase form of `w`, if it has one that comes back to `w`.
+#[inline]
+fn marker(w: &str, a: Alphabet) -> Option<(u8, String)> {
+    let lower = w.to_lowercase();
+    // Still a single word, so that the marker finds it again
+    if lower == w || split(lower.as_bytes(), a).len() != 1 {
+        return None;
+    }
+
+    if title(&lower) == w {
+        Some((TITLE, lower))
+    } else if lower.to_uppercase() == w {
+        Some((UPPER, lower))
+    } else {
+        None
+    }
+}
+
+#[inline]
+pub fn fold(b: &[u8], a: Alphabet) -> Vec<u8> {
+    let mut r = Vec::with_capacity(b.len() + b.len() / 16);
+
+    for t in split(b, a) {
+        let folded = is_word(t, a)
+            .then(|| std::str::from_utf8(t).ok().and_then(|w| marker(w, a)))
+            .flatten();
+
+        if let Some((m, lower)) = folded {
+            r.push(m);
+            r.extend_from_slice(lower.as_bytes());
+            continue;
+        }
+        for &c in t {
+            if c == TITLE || c == UPPER {
+                r.push(c)
+            }
+            r.push(c)
+        }
+    }
+    r
+}
+
+/// Reverses `fold`.
+#[inline]
+pub fn unfold(b: &[u8], a: Alphabet) -> Result<Vec<u8>> {
+    let dangling = || Error::corrupted("Dangling case marker.");
+
+    let mut r = Vec::with_capacity(b.len());
+    let mut pending = None;
+
+    for t in split(b, a) {
+        if let Some(m) = pending.take() {
+            let w = std::str::from_utf8(t).ok().filter(|_| is_word(t, a)).ok_or_else(dangling)?;
+            let w = if m == TITLE { title(w) } else { w.to_uppercase() };
+            r.extend_from_slice(w.as_bytes());
+            continue;
+        }
+
+        let mut bytes = t.iter().peekable();
+        while let Some(&c) = bytes.next() {
+            if c != TITLE && c != UPPER || bytes.next_if_eq(&&c).is_some() {
+                r.push(c)
+            } else if bytes.peek().is_none() {
+                pending = Some(c)
+            } else {
+                return Err(dangling());
+            }
+        }
+    }
+
+    match pending {
+        Some(_) => Err(dangling()),
+        None => Ok(r),
+    }
+}
+
+#[test]
+fn case_cases() {
+    let a = Alphabet::Letters;
+    let s = "Aliquam aliquam ALIQUAM AliQuam I a \u{1c}Lorem\u{1d}\u{1c} (Ünd ÜND) İstanbul ß";
+
+    let folded = fold(s.as_bytes(), a);
+    let text = std::str::from_utf8(&folded).unwrap();
+    assert_eq!(text.matches("aliquam").count(), 3);
+    assert!(text.contains("AliQuam"));
+    assert!(text.contains("(\u{1c}ünd \u{1d}ünd)"));
+    assert_eq!(unfold(&folded, a).unwrap(), s.as_bytes());
+
+    let binary = b"\xffAb\x1c\x1d\xfe";
+    assert_eq!(unfold(&fold(binary, a), a).unwrap(), binary);
+
+    // Markers before something other than a word
+    assert!(unfold(b"\x1c", a).is_err());
+    assert!(unfold(b"\x1c word", a).is_err());
+    assert!(unfold(b"\x1c\x1dword", a).is_err());
+}
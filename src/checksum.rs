@@ -0,0 +1,98 @@
+use crate::{err::Error, Result};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use xxhash_rust::xxh64::Xxh64;
+
+/// Integrity check recorded in the archive header, digests are stored raw.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Checksum {
+    None,
+    Crc32,
+    Xxh64,
+    Sha256,
+}
+
+impl Checksum {
+    #[inline]
+    pub fn from(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Crc32),
+            2 => Ok(Self::Xxh64),
+            3 => Ok(Self::Sha256),
+            _ => Err(Error::corrupted("Unknown checksum.")),
+        }
+    }
+
+    #[inline]
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// Length of the digest in bytes.
+    #[inline]
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Crc32 => 4,
+            Self::Xxh64 => 8,
+            Self::Sha256 => 32,
+        }
+    }
+
+    /// Digest of all `parts` as if they were one slice.
+    #[inline]
+    pub fn digest(self, parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Self::None => Vec::new(),
+            Self::Crc32 => {
+                let mut h = crc32fast::Hasher::new();
+                parts.iter().for_each(|p| h.update(p));
+                h.finalize().to_le_bytes().to_vec()
+            }
+            Self::Xxh64 => {
+                let mut h = Xxh64::new(0);
+                parts.iter().for_each(|p| h.update(p));
+                h.digest().to_le_bytes().to_vec()
+            }
+            Self::Sha256 => {
+                let mut h = Sha256::new();
+                parts.iter().for_each(|p| h.update(p));
+                h.finalize().to_vec()
+            }
+        }
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "crc32" => Ok(Self::Crc32),
+            "xxh64" => Ok(Self::Xxh64),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(Error::invalid_args("Expected none, crc32, xxh64 or sha256 checksum!")),
+        }
+    }
+}
+
+#[test]
+fn checksum_cases() {
+    let all = [Checksum::None, Checksum::Crc32, Checksum::Xxh64, Checksum::Sha256];
+
+    for c in all {
+        assert_eq!(Checksum::from(c.id()).unwrap(), c);
+        assert_eq!(c.digest(&[b"word", b"zip"]).len(), c.digest_len());
+        assert_eq!(c.digest(&[b"word", b"zip"]), c.digest(&[b"wordzip"]));
+    }
+    assert!(Checksum::from(4).is_err());
+
+    // Known answers for "123456789"
+    assert_eq!(Checksum::Crc32.digest(&[b"123456789"]), 0xcbf43926u32.to_le_bytes());
+    assert_eq!(
+        Checksum::Sha256.digest(&[b"123456789"])[..4],
+        [0x15, 0xe2, 0xb0, 0xd3]
+    );
+}
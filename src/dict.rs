@@ -0,0 +1,120 @@
+//! Shared dictionaries, trained once on a sample of similar documents.
+//!
+//! Archives made with one keep only its id instead of a table per block:
+//!
+//! ```text
+//! magic     4   b"WZDI"
+//! version   1
+//! alphabet  1   `Alphabet` of the codes
+//! table     n   entries of the `WordsMap`
+//! ```
+
+use crate::{
+    alphabet::Alphabet,
+    checksum::Checksum,
+    err::Error,
+    indexation::WordsMap,
+    proc::{split, words_block},
+    Result,
+};
+use std::io::Read;
+
+pub const MAGIC: &[u8; 4] = b"WZDI";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub struct Dict {
+    id: u64,
+    alphabet: Alphabet,
+    map: WordsMap,
+}
+
+impl Dict {
+    /// Selects the words and phrases of `sample` like a block does, but with
+    /// the table paid for only once.
+    #[inline]
+    pub fn train(sample: &[u8], alphabet: Alphabet, max_ngram: usize) -> Result<Self> {
+        if sample.is_empty() {
+            return Err(Error::EmptyInput);
+        }
+
+        let (map, _) = words_block(&split(sample, alphabet), alphabet, max_ngram)
+            .ok_or_else(|| Error::invalid_args("The sample has no words worth a dictionary!"))?;
+        Ok(Self::from(alphabet, map))
+    }
+
+    #[inline]
+    fn from(alphabet: Alphabet, map: WordsMap) -> Self {
+        // Same table, same id, whoever trained it
+        let digest = Checksum::Xxh64.digest(&[&[alphabet.id()], &map.to_bytes()]);
+        Dict {
+            id: u64::from_le_bytes(digest.try_into().unwrap()),
+            alphabet,
+            map,
+        }
+    }
+
+    #[inline]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&MAGIC[..], &[VERSION, self.alphabet.id()], &self.map.to_bytes()].concat()
+    }
+
+    #[inline]
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        let corrupted = || Error::corrupted("Not a wordzip dictionary.");
+
+        let (magic, b) = b.split_at_checked(MAGIC.len()).ok_or_else(corrupted)?;
+        let [version, alphabet, table @ ..] = b else { return Err(corrupted()) };
+        if magic != MAGIC {
+            return Err(corrupted());
+        }
+        if *version != VERSION {
+            return Err(Error::UnsupportedVersion(*version));
+        }
+
+        Ok(Self::from(Alphabet::from(*alphabet)?, WordsMap::from_bytes(table)?))
+    }
+
+    #[inline]
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let mut b = Vec::new();
+        r.read_to_end(&mut b)?;
+        Self::from_bytes(&b)
+    }
+
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[inline]
+    pub fn alphabet(&self) -> Alphabet {
+        self.alphabet
+    }
+
+    #[inline]
+    pub(crate) fn map(&self) -> &WordsMap {
+        &self.map
+    }
+}
+
+#[test]
+fn dict_cases() {
+    let sample = std::fs::read("short").unwrap();
+
+    for a in [Alphabet::Letters, Alphabet::PrivateUse] {
+        let d = Dict::train(&sample, a, 3).unwrap();
+        assert!(d.map.iter().len() > 0);
+
+        let b = d.to_bytes();
+        assert!(b.starts_with(MAGIC));
+        assert_eq!(Dict::from_bytes(&b).unwrap(), d);
+        assert_eq!(Dict::train(&sample, a, 3).unwrap().id(), d.id());
+    }
+
+    assert!(matches!(Dict::train(b"", Alphabet::Letters, 3), Err(Error::EmptyInput)));
+    assert!(Dict::train(b"1 2 3", Alphabet::Letters, 3).is_err());
+    assert!(Dict::from_bytes(b"WZIP\x01\x00").is_err());
+    assert!(Dict::from_bytes(b"WZD").is_err());
+    assert!(matches!(Dict::from_bytes(b"WZDI\x02\x00"), Err(Error::UnsupportedVersion(2))));
+}
@@ -1,23 +1,119 @@
-use std::fmt::Display;
+use args::ArgsError;
+use std::{fmt::Display, io};
 
+/// Everything that can go wrong, each kind has its own process exit code.
 #[derive(Debug)]
-pub struct Error {
-    desc: String,
+pub enum Error {
+    /// Reading the input or writing the output failed.
+    Io(io::Error),
+    /// Bad command line.
+    InvalidArgs(String),
+    /// The output file exists and `-f` was not given.
+    OutputExists(String),
+    /// There is no archive to decompress.
+    EmptyInput,
+    /// The archive can not be read, with details on what is wrong.
+    Corrupted(String),
+    /// The archive was written by a newer format version.
+    UnsupportedVersion(u8),
+    /// A block does not match its recorded checksum.
+    ChecksumMismatch,
+    /// A block was restored, but does not match the checksum of the original.
+    DataMismatch,
 }
 
 impl Error {
+    /// Process exit code of this kind of error:
+    ///
+    /// | code | kind               |
+    /// |------|--------------------|
+    /// | 1    | Io                 |
+    /// | 2    | InvalidArgs        |
+    /// | 3    | OutputExists       |
+    /// | 4    | EmptyInput         |
+    /// | 5    | Corrupted          |
+    /// | 6    | UnsupportedVersion |
+    /// | 7    | ChecksumMismatch   |
+    /// | 8    | DataMismatch       |
     #[inline]
-    pub fn new(scope: &str, msg: &str) -> Self {
-        Self {
-            desc: format!("{scope}: {msg}"),
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io(_) => 1,
+            Self::InvalidArgs(_) => 2,
+            Self::OutputExists(_) => 3,
+            Self::EmptyInput => 4,
+            Self::Corrupted(_) => 5,
+            Self::UnsupportedVersion(_) => 6,
+            Self::ChecksumMismatch => 7,
+            Self::DataMismatch => 8,
         }
     }
+
+    #[inline]
+    pub fn corrupted(msg: &str) -> Self {
+        Self::Corrupted(msg.to_string())
+    }
+
+    #[inline]
+    pub fn invalid_args(msg: &str) -> Self {
+        Self::InvalidArgs(msg.to_string())
+    }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.desc)
+        match self {
+            Self::Io(e) => write!(f, "io: {e}"),
+            Self::InvalidArgs(msg) => write!(f, "args: {msg}"),
+            Self::OutputExists(path) => write!(
+                f,
+                "path: File `{path}` already exists!\n\
+                        Specify other file or use `-f` for override existing file."
+            ),
+            Self::EmptyInput => write!(f, "input: The input is empty!"),
+            Self::Corrupted(msg) => write!(f, "file: File is corrupted! {msg}"),
+            Self::UnsupportedVersion(v) => write!(f, "file: Unsupported format version {v}!"),
+            Self::ChecksumMismatch => write!(f, "file: Checksum mismatch!"),
+            Self::DataMismatch => write!(f, "data: Decompressed data mismatch!"),
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ArgsError> for Error {
+    fn from(e: ArgsError) -> Self {
+        Self::InvalidArgs(e.to_string())
+    }
+}
+
+#[test]
+fn exit_codes() {
+    let all = [
+        Error::Io(io::Error::other("x")),
+        Error::invalid_args("x"),
+        Error::OutputExists("x".into()),
+        Error::EmptyInput,
+        Error::corrupted("x"),
+        Error::UnsupportedVersion(2),
+        Error::ChecksumMismatch,
+        Error::DataMismatch,
+    ];
+
+    // Distinct and never the success code
+    let codes = all.iter().map(Error::exit_code).collect::<Vec<_>>();
+    assert_eq!(codes, (1..=8).collect::<Vec<_>>());
+}
@@ -0,0 +1,338 @@
+//! Archive container.
+//!
+//! All integers are little-endian:
+//!
+//! ```text
+//! magic      4   b"WZIP"
+//! version    1
+//! flags      1   stages the blocks were coded with, see the block flags
+//! checksum   1   `Checksum` used for every block
+//! alphabet   1   `Alphabet` of the dictionary codes
+//! dict id    8   id of the shared dictionary, only with `DICT`
+//! block*         one per input block, each with its own dictionary
+//!   size     4   length of the original block, never 0
+//!   flags    1   `STORED` when no tokens were coded, `CASE`, `GAPS`, `DICT`, `BPE`,
+//!                `PPM`, `LZ77`, `HUFFMAN`
+//!   hash     n   digest of huffman, dict and payload, length depends on the checksum
+//!   check    n   digest of the original block
+//!   huffman  128 code lengths, only with `HUFFMAN`
+//!   dict     4 + n
+//!   payload  4 + n
+//! end        4   zero size
+//! total      8   length of the original file
+//! ```
+//!
+//! Huffman code lengths are kept per block, not in the header: the header
+//! is written before any block is read, and every block is coded and
+//! decoded on its own, by its own byte frequencies.
+
+use crate::{alphabet::Alphabet, checksum::Checksum, err::Error, huffman::TABLE_LEN, Result};
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"WZIP";
+pub const VERSION: u8 = 1;
+
+/// Block flag: nothing was gained, the payload is kept as is.
+pub const STORED: u8 = 1;
+/// Block flag: the payload is Huffman coded.
+pub const HUFFMAN: u8 = 2;
+/// Block flag: the dictionary is a merge table and the payload its symbols.
+pub const BPE: u8 = 4;
+/// Block flag: the payload is made of back-references, before any Huffman coding.
+pub const LZ77: u8 = 8;
+/// Block flag: the dictionary is a vocabulary and the payload its arithmetic code.
+pub const PPM: u8 = 16;
+/// Block flag: words were case folded before the method, see `case`.
+pub const CASE: u8 = 32;
+/// Block flag: separators were coded after the case folding, see `gaps`.
+pub const GAPS: u8 = 64;
+/// Block flag: words were coded by the shared dictionary, the dict section is empty.
+pub const DICT: u8 = 128;
+
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    pub flags: u8,
+    pub checksum: Checksum,
+    pub alphabet: Alphabet,
+    /// Id of the shared dictionary, set exactly when the flags have `DICT`.
+    pub dict: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    pub size: u32,
+    pub flags: u8,
+    pub hash: Vec<u8>,
+    pub check: Vec<u8>,
+    pub huffman: Vec<u8>,
+    pub dict: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl Header {
+    #[inline]
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION, self.flags, self.checksum.id(), self.alphabet.id()])?;
+        if let Some(id) = self.dict {
+            w.write_all(&id.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn read<R: Read>(r: &mut Reader<R>) -> Result<Self> {
+        let magic = r.upto(MAGIC.len())?;
+        if magic.is_empty() {
+            return Err(Error::EmptyInput);
+        }
+        if magic != MAGIC {
+            return Err(Error::corrupted("Not a wordzip archive."));
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let flags = r.u8()?;
+        Ok(Header {
+            flags,
+            checksum: Checksum::from(r.u8()?)?,
+            alphabet: Alphabet::from(r.u8()?)?,
+            dict: if flags & DICT != 0 { Some(r.u64()?) } else { None },
+        })
+    }
+}
+
+impl Block {
+    #[inline]
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(&self.size.to_le_bytes())?;
+        w.write_all(&[self.flags])?;
+        w.write_all(&self.hash)?;
+        w.write_all(&self.check)?;
+        w.write_all(&self.huffman)?;
+        w.write_all(&(self.dict.len() as u32).to_le_bytes())?;
+        w.write_all(&self.dict)?;
+        w.write_all(&(self.payload.len() as u32).to_le_bytes())?;
+        w.write_all(&self.payload)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn write_end<W: Write>(w: &mut W, total: u64) -> Result<()> {
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&total.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Reads the next block, `None` once the end marker is reached.
+    #[inline]
+    pub fn read<R: Read>(r: &mut Reader<R>, h: &Header) -> Result<Option<Self>> {
+        let size = r.u32()?;
+        if size == 0 {
+            return Ok(None);
+        }
+
+        let flags = r.u8()?;
+        let hash = r.bytes(h.checksum.digest_len())?;
+        let check = r.bytes(h.checksum.digest_len())?;
+        let huffman = if flags & HUFFMAN != 0 { r.bytes(TABLE_LEN)? } else { Vec::new() };
+        let n = r.u32()? as usize;
+        let dict = r.bytes(n)?;
+        let n = r.u32()? as usize;
+        let payload = r.bytes(n)?;
+
+        Ok(Some(Block {
+            size,
+            flags,
+            hash,
+            check,
+            huffman,
+            dict,
+            payload,
+        }))
+    }
+}
+
+#[inline]
+pub fn push_varint(b: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        b.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    b.push(v as u8)
+}
+
+/// Reads archive fields, a premature end of input is reported as corruption.
+///
+/// Lengths read here, and the sizes of blocks, are never preallocated by
+/// the readers and decoders: a corrupted one must not exhaust memory.
+pub struct Reader<R: Read> {
+    r: R,
+}
+
+impl<R: Read> Reader<R> {
+    #[inline]
+    pub fn from(r: R) -> Self {
+        Reader { r }
+    }
+
+    #[inline]
+    pub fn bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        let b = self.upto(n)?;
+
+        if b.len() != n {
+            return Err(Error::corrupted("Unexpected end of file."));
+        }
+        Ok(b)
+    }
+
+    /// Reads `n` bytes or whatever is left before the end.
+    #[inline]
+    pub fn upto(&mut self, n: usize) -> Result<Vec<u8>> {
+        let mut b = Vec::new();
+        (&mut self.r).take(n as u64).read_to_end(&mut b)?;
+        Ok(b)
+    }
+
+    #[inline]
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    #[inline]
+    pub fn u32(&mut self) -> Result<u32> {
+        let mut b = [0; 4];
+        self.exact(&mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    #[inline]
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+        self.exact(&mut b)?;
+        Ok(u64::from_le_bytes(b))
+    }
+
+    /// LEB128 encoded integer, see `push_varint`.
+    #[inline]
+    pub fn varint(&mut self) -> Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(Error::corrupted("Overlong integer."))
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    /// Tells whether everything was read, consumes a byte otherwise.
+    #[inline]
+    pub fn at_end(&mut self) -> Result<bool> {
+        Ok(self.r.read(&mut [0])? == 0)
+    }
+
+    #[inline]
+    fn exact(&mut self, b: &mut [u8]) -> Result<()> {
+        match self.r.read_exact(b) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(Error::corrupted("Unexpected end of file."))
+            }
+            r => Ok(r?),
+        }
+    }
+}
+
+#[test]
+fn archive_cases() {
+    let block = Block {
+        size: 11,
+        flags: 0,
+        hash: vec![1, 2, 3, 4],
+        check: vec![5, 6, 7, 8],
+        huffman: Vec::new(),
+        dict: b"a:word\n".to_vec(),
+        payload: b"a a a".to_vec(),
+    };
+
+    let header = Header {
+        flags: 0,
+        checksum: Checksum::Crc32,
+        alphabet: Alphabet::PrivateUse,
+        dict: None,
+    };
+
+    let mut b = Vec::new();
+    header.write(&mut b).unwrap();
+    block.write(&mut b).unwrap();
+    block.write(&mut b).unwrap();
+    let coded = Block {
+        flags: HUFFMAN,
+        huffman: vec![0x88; TABLE_LEN],
+        ..block.clone()
+    };
+    coded.write(&mut b).unwrap();
+    Block::write_end(&mut b, 33).unwrap();
+
+    assert_eq!(&b[..4], MAGIC);
+
+    let mut r = Reader::from(b.as_slice());
+    assert_eq!(Header::read(&mut r).unwrap(), header);
+    assert_eq!(Block::read(&mut r, &header).unwrap(), Some(block));
+    assert!(Block::read(&mut r, &header).unwrap().is_some());
+    assert_eq!(Block::read(&mut r, &header).unwrap(), Some(coded));
+    assert_eq!(Block::read(&mut r, &header).unwrap(), None);
+    assert_eq!(r.u64().unwrap(), 33);
+    assert!(r.at_end().unwrap());
+
+    // Truncated, foreign and future archives
+    let mut r = Reader::from(&b[..b.len() - 13]);
+    Header::read(&mut r).unwrap();
+    Block::read(&mut r, &header).unwrap();
+    Block::read(&mut r, &header).unwrap();
+    assert!(Block::read(&mut r, &header).is_err());
+
+    assert!(Header::read(&mut Reader::from(&b"PK\x03\x04"[..])).is_err());
+    assert!(matches!(Header::read(&mut Reader::from(&b""[..])), Err(Error::EmptyInput)));
+    let mut future = b.clone();
+    future[4] = VERSION + 1;
+    assert!(Header::read(&mut Reader::from(future.as_slice())).is_err());
+
+    // The id of a shared dictionary follows the header
+    let shared = Header {
+        flags: DICT,
+        dict: Some(0x0123_4567_89ab_cdef),
+        ..header
+    };
+    let mut b = Vec::new();
+    shared.write(&mut b).unwrap();
+    assert_eq!(b.len(), 16);
+    assert_eq!(Header::read(&mut Reader::from(b.as_slice())).unwrap(), shared);
+}
+
+#[test]
+fn varint_cases() {
+    let values = [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX];
+
+    let mut b = Vec::new();
+    values.iter().for_each(|v| push_varint(&mut b, *v));
+    assert_eq!(&b[..4], [0, 1, 127, 0x80]);
+
+    let mut r = Reader::from(b.as_slice());
+    for v in values {
+        assert_eq!(r.varint().unwrap(), v);
+    }
+    assert!(r.at_end().unwrap());
+
+    // Unterminated and overlong
+    assert!(Reader::from(&[0x80u8][..]).varint().is_err());
+    assert!(Reader::from(&[0xffu8; 11][..]).varint().is_err());
+}
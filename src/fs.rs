@@ -1,27 +1,205 @@
-use crate::Result;
-use std::{fmt::Display, fs::File, io::Write, path::PathBuf as Path};
+use memmap2::Mmap;
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufReader, BufWriter, Cursor, Read, Write},
+    path::PathBuf as Path,
+};
 
-#[derive(Debug)]
-pub struct ToSave {
-    content: String,
+/// An input file mapped in memory, or read through a buffer when it is not
+/// a regular one, like stdin or a pipe.
+pub enum Input<R: Read> {
+    Mapped(Cursor<Mmap>),
+    Buffered(R),
 }
 
-impl ToSave {
+impl<R: Read> Read for Input<R> {
     #[inline]
-    pub fn new(content: String) -> Self {
-        Self { content }
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Mapped(c) => c.read(buf),
+            Self::Buffered(r) => r.read(buf),
+        }
+    }
+}
+
+/// Opens `path` for reading, stdin when there is no path.
+#[inline]
+pub fn open(path: Option<&Path>) -> io::Result<Box<dyn Read>> {
+    Ok(match path {
+        Some(p) => Box::new(BufReader::new(File::open(p)?)),
+        None => Box::new(io::stdin().lock()),
+    })
+}
+
+/// Maps `path` in memory, falls back to `open` for anything else.
+#[inline]
+pub fn input(path: Option<&Path>) -> io::Result<Input<Box<dyn Read>>> {
+    let Some(p) = path else { return open(None).map(Input::Buffered) };
+    let file = File::open(p)?;
+
+    if file.metadata()?.is_file() {
+        // The file is only read, changing it meanwhile is up to whoever does
+        if let Ok(map) = unsafe { Mmap::map(&file) } {
+            return Ok(Input::Mapped(Cursor::new(map)));
+        }
+    }
+    Ok(Input::Buffered(Box::new(BufReader::new(file))))
+}
+
+/// Creates `path` for writing, stdout when there is no path.
+#[inline]
+pub fn create(path: Option<&Path>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(p) => Box::new(BufWriter::new(File::create(p)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    })
+}
+
+/// Whether `a` and `b` name one existing file, through links or not.
+#[inline]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    let (Ok(a), Ok(b)) = (a.canonicalize(), b.canonicalize()) else { return false };
+    if a == b {
+        return true;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let (Ok(a), Ok(b)) = (a.metadata(), b.metadata()) {
+            return (a.dev(), a.ino()) == (b.dev(), b.ino());
+        }
+    }
+    false
+}
+
+/// Cuts the input into blocks of at most `size` bytes.
+///
+/// Blocks end after the last ASCII whitespace when there is one, so words
+/// are not torn apart between two dictionaries. They are borrowed from a
+/// mapped input and read into memory from any other.
+pub struct Blocks<R: Read> {
+    input: Input<R>,
+    size: usize,
+    carry: Vec<u8>,
+    eof: bool,
+}
+
+/// Where a full block is cut.
+#[inline]
+fn cut(b: &[u8]) -> usize {
+    b.iter().rposition(u8::is_ascii_whitespace).map_or(b.len(), |p| p + 1)
+}
+
+impl<R: Read> Blocks<R> {
+    #[inline]
+    pub fn from(input: Input<R>, size: usize) -> Self {
+        Blocks {
+            input,
+            size,
+            carry: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Next block of a mapped input, cut the same as a read one.
+    #[inline]
+    fn slice(c: &mut Cursor<Mmap>, size: usize) -> (usize, usize) {
+        let start = c.position() as usize;
+        let rest = &c.get_ref()[start..];
+        let len = match rest.len() < size {
+            true => rest.len(),
+            false => cut(&rest[..size]),
+        };
+        c.set_position((start + len) as u64);
+        (start, start + len)
+    }
+
+    #[inline]
+    fn fill(&mut self) -> io::Result<Vec<u8>> {
+        let mut b = std::mem::take(&mut self.carry);
+        let want = (self.size - b.len()) as u64;
+
+        if (&mut self.input).take(want).read_to_end(&mut b)? < want as usize {
+            self.eof = true;
+        }
+        if self.eof {
+            return Ok(b);
+        }
+
+        self.carry = b.split_off(cut(&b));
+        Ok(b)
+    }
+
+    #[inline]
+    fn read(&mut self, n: usize) -> io::Result<Vec<Cow<'_, [u8]>>> {
+        let mut r = Vec::with_capacity(n);
+        while r.len() < n && !(self.eof && self.carry.is_empty()) {
+            match self.fill()? {
+                b if b.is_empty() => break,
+                b => r.push(Cow::Owned(b)),
+            }
+        }
+        Ok(r)
     }
 
+    /// Up to `n` next blocks, none at the end of the input.
     #[inline]
-    pub fn save(self, path: Path) -> Result<()> {
-        let mut f = File::create(path)?;
-        f.write_all(self.content.as_bytes())?;
-        Ok(())
+    pub fn batch(&mut self, n: usize) -> io::Result<Vec<Cow<'_, [u8]>>> {
+        if let Input::Buffered(_) = self.input {
+            return self.read(n);
+        }
+        let Input::Mapped(c) = &mut self.input else { unreachable!() };
+
+        let ranges = (0..n)
+            .map(|_| Self::slice(c, self.size))
+            .take_while(|(start, end)| start < end)
+            .collect::<Vec<_>>();
+        Ok(ranges.into_iter().map(|(s, e)| Cow::Borrowed(&c.get_ref()[s..e])).collect())
     }
 }
 
-impl Display for ToSave {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.content)
+#[test]
+fn blocks_cases() {
+    fn blocks<R: Read>(input: Input<R>, size: usize) -> Vec<Vec<u8>> {
+        let mut blocks = Blocks::from(input, size);
+        let mut r = Vec::new();
+        loop {
+            match blocks.batch(3).unwrap() {
+                b if b.is_empty() => break r,
+                b => r.extend(b.into_iter().map(Cow::into_owned)),
+            }
+        }
+    }
+
+    let s = b"lorem ipsum dolor sit amet, consectetur";
+    let b = blocks(Input::Buffered(&s[..]), 16);
+    assert_eq!(b.concat(), s);
+    assert_eq!(b[0], b"lorem ipsum ");
+    assert!(b.iter().all(|b| b.len() <= 16));
+
+    // No whitespace to cut at
+    let s = [b'x'; 40];
+    let b = blocks(Input::Buffered(&s[..]), 16);
+    assert_eq!(b.iter().map(Vec::len).collect::<Vec<_>>(), vec![16, 16, 8]);
+
+    // A mapped file is cut the same as a read one
+    let path = Path::from("short");
+    let Input::Mapped(_) = input(Some(&path)).unwrap() else { panic!("short is not mapped") };
+    for size in [1 << 10, 4 << 10, 1 << 20] {
+        let mapped = blocks(input(Some(&path)).unwrap(), size);
+        assert_eq!(mapped, blocks(Input::Buffered(open(Some(&path)).unwrap()), size));
+        assert_eq!(mapped.concat(), std::fs::read(&path).unwrap());
     }
 }
+
+#[test]
+fn same_file_cases() {
+    let short = Path::from("short");
+
+    assert!(same_file(&short, &short));
+    assert!(same_file(&short, &Path::from("src/../short")));
+    assert!(!same_file(&short, &Path::from("Cargo.toml")));
+    assert!(!same_file(&short, &Path::from("asldfasdhfjklashfljkas.adsa")));
+}
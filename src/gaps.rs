@@ -0,0 +1,145 @@
+//! Coding of the separators between words.
+//!
+//! A single space is a byte already, but runs like `", "` or `".\n"` take
+//! several. The most frequent runs of a block become single control bytes
+//! of `CODES`, every literal one of them is doubled. Coded data is
+//! `runs (u8) | (length (u8) | run)* | text`.
+
+use crate::{
+    alphabet::Alphabet,
+    err::Error,
+    format::Reader,
+    proc::{is_word, split},
+    Result,
+};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Control bytes no other stage gives a meaning.
+const CODES: [u8; 24] = [
+    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14,
+    0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1e, 0x1f, 0x7f,
+];
+/// Longest run that gets a code.
+const MAX_RUN: usize = 16;
+
+/// Longest table, for bounding the coded size.
+pub const MAX_TABLE: usize = 1 + CODES.len() * (1 + MAX_RUN);
+
+#[inline]
+fn code(b: u8) -> Option<usize> {
+    CODES.iter().position(|&c| c == b)
+}
+
+/// Words and the maximal runs of anything else between them.
+#[inline]
+fn runs(b: &[u8], a: Alphabet) -> Vec<(bool, &[u8])> {
+    let mut r: Vec<(bool, &[u8])> = Vec::new();
+    let mut start = 0;
+
+    for t in split(b, a) {
+        let end = start + t.len();
+        let gap = !is_word(t, a);
+        match r.last_mut() {
+            Some((true, run)) if gap => *run = &b[end - t.len() - run.len()..end],
+            _ => r.push((gap, t)),
+        }
+        start = end;
+    }
+    r
+}
+
+#[inline]
+pub fn encode(b: &[u8], a: Alphabet) -> Vec<u8> {
+    let runs = runs(b, a);
+
+    // Every use saves all but a byte, the entry costs its length and one
+    let mut counts: HashMap<&[u8], (usize, usize)> = HashMap::new();
+    for (i, &(_, run)) in runs.iter().filter(|(gap, run)| *gap && run.len() > 1).enumerate() {
+        if run.len() <= MAX_RUN {
+            counts.entry(run).or_insert((0, i)).0 += 1;
+        }
+    }
+    let mut table = counts
+        .into_iter()
+        .map(|(run, (n, first))| ((n * (run.len() - 1)) as i64 - run.len() as i64 - 1, first, run))
+        .filter(|&(savings, _, _)| savings > 0)
+        .collect::<Vec<_>>();
+    table.sort_by_key(|&(savings, first, _)| (Reverse(savings), first));
+    table.truncate(CODES.len());
+
+    let mut r = Vec::with_capacity(b.len() + MAX_TABLE);
+    r.push(table.len() as u8);
+    for (_, _, run) in &table {
+        r.push(run.len() as u8);
+        r.extend_from_slice(run);
+    }
+
+    let codes: HashMap<&[u8], u8> =
+        table.iter().zip(CODES).map(|(&(_, _, run), c)| (run, c)).collect();
+    for (gap, run) in runs {
+        if let Some(&c) = codes.get(run).filter(|_| gap) {
+            r.push(c);
+            continue;
+        }
+        for &c in run {
+            if code(c).is_some() {
+                r.push(c)
+            }
+            r.push(c)
+        }
+    }
+    r
+}
+
+/// Reverses `encode`.
+#[inline]
+pub fn decode(b: &[u8]) -> Result<Vec<u8>> {
+    let corrupted = || Error::corrupted("Malformed separator codes.");
+
+    let mut r = Reader::from(b);
+    let n = r.u8()? as usize;
+    if n > CODES.len() {
+        return Err(corrupted());
+    }
+    let mut table = Vec::with_capacity(n);
+    for _ in 0..n {
+        let len = r.u8()? as usize;
+        if !(2..=MAX_RUN).contains(&len) {
+            return Err(corrupted());
+        }
+        table.push(r.bytes(len)?);
+    }
+
+    let text = r.into_inner();
+    let mut out = Vec::with_capacity(text.len() * 2);
+    let mut bytes = text.iter().peekable();
+    while let Some(&c) = bytes.next() {
+        match code(c) {
+            Some(_) if bytes.next_if_eq(&&c).is_some() => out.push(c),
+            Some(i) => out.extend_from_slice(table.get(i).ok_or_else(corrupted)?),
+            None => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+#[test]
+fn gaps_cases() {
+    let a = Alphabet::Letters;
+    let text = std::fs::read("short").unwrap();
+
+    let coded = encode(&text, a);
+    assert!(coded.len() < text.len() * 97 / 100);
+    assert_eq!(decode(&coded).unwrap(), text);
+
+    let odd = b"a, b, c, d, e, f\x01\x01, g\x7f \xff, h\x1f, i, \x01\x01";
+    for b in [&odd[..], b"", b", , ,", b"word"] {
+        assert_eq!(decode(&encode(b, a)).unwrap(), b);
+    }
+
+    // Too many runs, a run too short and a code without a run
+    assert!(decode(&[25]).is_err());
+    assert!(decode(&[1, 1, b' ']).is_err());
+    assert!(decode(&[0, b'a', 0x01, b'b']).is_err());
+}
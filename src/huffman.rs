@@ -0,0 +1,220 @@
+//! Canonical Huffman coding of bytes.
+//!
+//! Only the code length of every byte value is stored, the codes themselves
+//! follow from the lengths. Coded data is `length (varint) | bits`, the bits
+//! are packed starting from the most significant one.
+
+use crate::{
+    err::Error,
+    format::{push_varint, Reader},
+    Result,
+};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Longest code, so that two lengths fit in a byte.
+const MAX_LEN: u8 = 15;
+
+/// Size of the stored code lengths.
+pub const TABLE_LEN: usize = 128;
+
+#[derive(Debug, PartialEq)]
+pub struct Huffman {
+    lengths: [u8; 256],
+}
+
+impl Huffman {
+    /// Builds the codes for the byte frequencies of `b`.
+    #[inline]
+    pub fn from(b: &[u8]) -> Self {
+        let mut freq = [0usize; 256];
+        b.iter().for_each(|&b| freq[b as usize] += 1);
+
+        // Flattening the frequencies until the tree is shallow enough keeps
+        // the codes close to optimal
+        let mut shift = 0;
+        loop {
+            let lengths = Self::lengths(&freq.map(|f| if f > 0 { (f >> shift).max(1) } else { 0 }));
+            if lengths.iter().all(|&l| l <= MAX_LEN) {
+                return Huffman { lengths };
+            }
+            shift += 1;
+        }
+    }
+
+    #[inline]
+    fn lengths(freq: &[usize; 256]) -> [u8; 256] {
+        let mut lengths = [0u8; 256];
+
+        let mut heap = BinaryHeap::new();
+        // Leaves are `0..256`, inner nodes are numbered from 256 on
+        let mut parent = vec![0usize; 256];
+        for (s, &f) in freq.iter().enumerate() {
+            if f > 0 {
+                heap.push(Reverse((f, s)));
+            }
+        }
+
+        if heap.len() == 1 {
+            let Reverse((_, s)) = heap.pop().unwrap();
+            lengths[s] = 1;
+            return lengths;
+        }
+
+        while heap.len() > 1 {
+            let Reverse((f1, a)) = heap.pop().unwrap();
+            let Reverse((f2, b)) = heap.pop().unwrap();
+            let node = parent.len();
+            parent.push(0);
+            parent[a] = node;
+            parent[b] = node;
+            heap.push(Reverse((f1 + f2, node)));
+        }
+
+        let root = parent.len() - 1;
+        for s in (0..256).filter(|&s| freq[s] > 0) {
+            let (mut n, mut depth) = (s, 0usize);
+            while n != root {
+                n = parent[n];
+                depth += 1;
+            }
+            lengths[s] = depth.min(u8::MAX as usize) as u8;
+        }
+        lengths
+    }
+
+    /// Code lengths as nibbles, the lower one first.
+    #[inline]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.lengths.chunks(2).map(|l| l[0] | l[1] << 4).collect()
+    }
+
+    #[inline]
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        if b.len() != TABLE_LEN {
+            return Err(Error::corrupted("Malformed Huffman table."));
+        }
+
+        let mut lengths = [0u8; 256];
+        for (i, &b) in b.iter().enumerate() {
+            lengths[2 * i] = b & 0xf;
+            lengths[2 * i + 1] = b >> 4;
+        }
+
+        // The codes must fit into the code space
+        let kraft: u32 = lengths.iter().filter(|&&l| l > 0).map(|&l| 1 << (MAX_LEN - l)).sum();
+        if kraft > 1 << MAX_LEN {
+            return Err(Error::corrupted("Malformed Huffman table."));
+        }
+        Ok(Huffman { lengths })
+    }
+
+    #[inline]
+    fn codes(&self) -> [u16; 256] {
+        let mut codes = [0u16; 256];
+        let mut code = 0u32;
+
+        for len in 1..=MAX_LEN {
+            for s in (0..256).filter(|&s| self.lengths[s] == len) {
+                codes[s] = code as u16;
+                code += 1;
+            }
+            code <<= 1;
+        }
+        codes
+    }
+
+    #[inline]
+    pub fn encode(&self, b: &[u8]) -> Vec<u8> {
+        let codes = self.codes();
+
+        let mut r = Vec::with_capacity(b.len() / 2 + 8);
+        push_varint(&mut r, b.len() as u64);
+
+        let (mut acc, mut bits) = (0u64, 0u32);
+        for &s in b {
+            let len = self.lengths[s as usize] as u32;
+            assert!(len > 0, "byte without a code");
+
+            acc = acc << len | codes[s as usize] as u64;
+            bits += len;
+            while bits >= 8 {
+                bits -= 8;
+                r.push((acc >> bits) as u8);
+            }
+        }
+        if bits > 0 {
+            r.push((acc << (8 - bits)) as u8);
+        }
+        r
+    }
+
+    #[inline]
+    pub fn decode(&self, b: &[u8]) -> Result<Vec<u8>> {
+        let corrupted = || Error::corrupted("Malformed Huffman data.");
+
+        let mut r = Reader::from(b);
+        let n = r.varint()? as usize;
+        let b = r.into_inner();
+
+        // Symbols ordered by code, and the amount of codes of every length
+        let mut count = [0i32; MAX_LEN as usize + 1];
+        let mut symbols = Vec::with_capacity(256);
+        for len in 1..=MAX_LEN {
+            for s in (0..=255u8).filter(|&s| self.lengths[s as usize] == len) {
+                count[len as usize] += 1;
+                symbols.push(s);
+            }
+        }
+
+        let mut bits = b.iter().flat_map(|&b| (0..8).rev().map(move |i| (b >> i) & 1));
+        let mut out = Vec::new();
+
+        while out.len() < n {
+            let (mut code, mut first, mut index) = (0i32, 0i32, 0usize);
+
+            for (len, &c) in count.iter().enumerate().skip(1) {
+                code |= bits.next().ok_or_else(corrupted)? as i32;
+                if code < first + c {
+                    out.push(symbols[index + (code - first) as usize]);
+                    break;
+                }
+                if len == MAX_LEN as usize {
+                    return Err(corrupted());
+                }
+                index += c as usize;
+                first = (first + c) << 1;
+                code <<= 1;
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[test]
+fn huffman_cases() {
+    let text = std::fs::read("short").unwrap();
+
+    for b in [&text[..], b"", b"aaaa", b"ab", &(0..=255).collect::<Vec<u8>>()] {
+        let h = Huffman::from(b);
+        let coded = h.encode(b);
+
+        let back = Huffman::from_bytes(&h.to_bytes()).unwrap();
+        assert_eq!(back, h);
+        assert_eq!(back.decode(&coded).unwrap(), b);
+    }
+    assert!(Huffman::from(&text).encode(&text).len() < text.len() * 2 / 3);
+
+    // Skewed frequencies still get codes of at most `MAX_LEN` bits
+    let skewed = (0..30u8).flat_map(|i| vec![i; 1 << (i / 2)]).collect::<Vec<u8>>();
+    let h = Huffman::from(&skewed);
+    assert!(h.lengths.iter().all(|&l| l <= MAX_LEN));
+    assert_eq!(h.decode(&h.encode(&skewed)).unwrap(), skewed);
+
+    // Truncated data and an over-subscribed table
+    let h = Huffman::from(&text);
+    let coded = h.encode(&text);
+    assert!(h.decode(&coded[..coded.len() - 1]).is_err());
+    assert!(Huffman::from_bytes(&[0x11; TABLE_LEN]).is_err());
+    assert!(Huffman::from_bytes(&[0x11; 3]).is_err());
+}
@@ -1,11 +1,16 @@
 use self::words::Word;
+use crate::{
+    err::Error,
+    format::{push_varint, Reader},
+    Result,
+};
 use itertools::Itertools;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::BTreeMap;
 use std::fmt::Display;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct WordsMap {
-    map: BTreeMap<CharSet, String>,
+    map: BTreeMap<CharSet, Vec<u8>>,
 }
 
 #[derive(Hash, Eq, PartialEq, PartialOrd, Ord, Clone, Debug)]
@@ -14,87 +19,122 @@ pub struct CharSet {
 }
 
 impl WordsMap {
+    /// Gives the words, best first, the shortest codes, see `Words::clear`.
     #[inline]
     pub fn from(w: Vec<Word>, c: Vec<char>) -> Self {
-        let mut map: BTreeMap<CharSet, String> = BTreeMap::new();
-
-        if w.len() < c.len() {
-            w.into_iter()
-                .zip(c.into_iter())
-                .map(|(w, c)| map.insert(c.into(), w.into_str()))
-                .collect_vec();
-            Self { map }
-        } else {
-            let (wl, cl) = (w.len(), c.len());
-            let mut c = Self::product(c, Self::amount_digraphs(wl, cl));
-
-            for w in w {
-                if c.is_empty() { break }
-                if w.word_type().is_verylong() {
-                    map.insert(c.pop_front().unwrap(), w.into_str());
-                } else {
-                    map.insert(c.pop_back().unwrap(), w.into_str());
-                }
-            }
+        let map = Self::codes(&c, w.len())
+            .into_iter()
+            .zip(w)
+            .map(|(ch, w)| (ch, w.into_bytes()))
+            .collect();
+
+        Self { map }
+    }
 
-            Self { map }
+    /// Stored as the amount of entries followed by every entry as
+    /// `code length (1) | code | word length (varint) | word`.
+    #[inline]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+        push_varint(&mut b, self.map.len() as u64);
+
+        for (ch, word) in self.iter() {
+            b.push(ch.as_bytes().len() as u8);
+            b.extend_from_slice(ch.as_bytes());
+            push_varint(&mut b, word.len() as u64);
+            b.extend_from_slice(word);
         }
+        b
     }
 
     #[inline]
-    pub fn from_plain(plain: String) -> Self {
-        let mut map: BTreeMap<CharSet, String> = BTreeMap::new();
-        plain
-            .lines()
-            .map(|s| {
-                if let Some((ch, word)) = s.split(':').collect_tuple() {
-                    map.insert(ch.into(), word.into())
-                } else {
-                    None
-                }
-            })
-            .collect_vec();
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        let corrupted = || Error::corrupted("Malformed dictionary.");
 
-        Self { map }
+        let mut map: BTreeMap<CharSet, Vec<u8>> = BTreeMap::new();
+        let mut r = Reader::from(b);
+
+        for _ in 0..r.varint()? {
+            let n = r.u8()? as usize;
+            let ch = r.bytes(n)?;
+            let ch = match std::str::from_utf8(&ch) {
+                Ok(ch) if (1..=2).contains(&ch.chars().count()) => CharSet::from(ch),
+                _ => return Err(corrupted()),
+            };
+
+            let n = r.varint()? as usize;
+            let word = r.bytes(n)?;
+
+            if word.is_empty() || map.insert(ch, word).is_some() {
+                return Err(corrupted());
+            }
+        }
+
+        if !r.at_end()? {
+            return Err(corrupted());
+        }
+        Ok(Self { map })
     }
 
+    /// Lengths in bytes and amounts of the codes made of `c`, shortest
+    /// first. Codes are single characters and pairs of them.
     #[inline]
-    fn amount_digraphs(words: usize, mut singles: usize) -> usize {
-        let mut needed = 0usize;
+    pub fn tiers(c: &[char]) -> Vec<(usize, usize)> {
+        let mut singles: BTreeMap<usize, usize> = BTreeMap::new();
+        c.iter().for_each(|c| *singles.entry(c.len_utf8()).or_default() += 1);
+
+        let mut tiers: BTreeMap<usize, usize> = BTreeMap::new();
+        for (&l, &n) in &singles {
+            *tiers.entry(l).or_default() += n;
+            for (&l2, &n2) in &singles {
+                *tiers.entry(l + l2).or_default() += n * n2;
+            }
+        }
+        tiers.into_iter().collect()
+    }
 
-        while words > singles {
-            singles += singles - 1;
-            needed += 1;
+    /// The first `n` codes made of `c`, in the order of `tiers`.
+    #[inline]
+    fn codes(c: &[char], n: usize) -> Vec<CharSet> {
+        let mut by_len: BTreeMap<usize, Vec<char>> = BTreeMap::new();
+        c.iter().for_each(|c| by_len.entry(c.len_utf8()).or_default().push(*c));
+
+        let mut r: Vec<CharSet> = Vec::with_capacity(n);
+        for (len, _) in Self::tiers(c) {
+            r.extend(by_len.get(&len).into_iter().flatten().map(CharSet::from));
+
+            for (l, first) in by_len.range(..len) {
+                if let Some(second) = by_len.get(&(len - l)) {
+                    let left = n.saturating_sub(r.len());
+                    r.extend(first.iter().cartesian_product(second).take(left).map(CharSet::from));
+                }
+            }
+            if r.len() >= n {
+                break;
+            }
         }
-        needed
+        r.truncate(n);
+        r
     }
 
     #[inline]
-    fn product(c: Vec<char>, amount: usize) -> VecDeque<CharSet> {
-        let basic = amount * c.len();
-        let (first, _) = c.split_at(amount);
-
-        let mut r = first
-            .iter()
-            .cartesian_product(&c)
-            .take(basic)
-            .map(|c| c.into())
-            .collect::<Vec<CharSet>>();
-
-        r.append(&mut c.iter().map(|c| c.into()).collect::<Vec<CharSet>>());
-        r.into()
+    pub fn is_code(&self, s: &[u8]) -> bool {
+        // No code is longer than two characters of four bytes
+        s.len() <= 8
+            && std::str::from_utf8(s)
+                .is_ok_and(|s| s.chars().count() <= 2 && self.map.contains_key(&s.into()))
     }
 
     #[inline]
-    pub fn iter(&self) -> std::collections::btree_map::Iter<CharSet, String> {
+    pub fn iter(&self) -> std::collections::btree_map::Iter<'_, CharSet, Vec<u8>> {
         self.map.iter()
     }
 }
 
 impl CharSet {
     #[inline]
-    pub fn as_str(&self) -> &String {
-        &self.set
+    pub fn as_bytes(&self) -> &[u8] {
+        self.set.as_bytes()
     }
 }
 
@@ -106,7 +146,7 @@ impl Display for CharSet {
 
 impl From<&str> for CharSet {
     fn from(set: &str) -> Self {
-        assert!(set.len() <= 2);
+        assert!(set.chars().count() <= 2);
         Self { set: set.into() }
     }
 }
@@ -137,73 +177,77 @@ impl From<(&char, &char)> for CharSet {
 
 pub mod words {
 
-    use itertools::Itertools;
+    use super::WordsMap;
+    use crate::alphabet::Alphabet;
+    use std::collections::{HashMap, HashSet};
 
     #[derive(Debug, Clone, PartialEq)]
     pub struct Words {
-        words: Vec<Word>,
+        words: Vec<Word>, // in the order of first appearance until `clear`
+        index: HashMap<Vec<u8>, usize>, // place of every word in `words`
         unused: Vec<char>,
+        used: HashSet<char>, // first characters of the tokens a code could be
 
         n: usize, // for Iterator implementation
     }
 
     impl Words {
         #[inline]
-        pub fn new() -> Self {
+        pub fn from(alphabet: Alphabet) -> Self {
             Words {
                 words: Vec::new(),
+                index: HashMap::new(),
                 n: 0,
-                unused: { let mut r = ('\u{41}'..'\u{5a}').collect_vec();
-                    r.append(&mut ('\u{61}'..'\u{7b}').collect_vec());
-                    r },
+                unused: alphabet.chars(),
+                used: HashSet::new(),
             }
         }
 
         #[inline]
-        pub fn insert(&mut self, k: &str, windos_mode: bool) {
-            if k.len() <= 2 {
-                let f = k.chars().next().unwrap();
-                self.unused.iter_mut().map(|c| if *c == f { *c = '\u{0}' }).for_each(drop);
+        pub fn insert(&mut self, k: &[u8], phrase: bool) {
+            // A token of up to two characters could be taken for a code
+            let short = if k.len() <= 8 { std::str::from_utf8(k).ok() } else { None };
+            if let Some(t) = short.filter(|t| t.chars().count() <= 2) {
+                self.used.extend(t.chars().next())
             }
-            if let Some(w) = self.words.iter_mut().find(|w| *w.str() == k) {
-                w.add()
-            } else if Self::word_check(k, windos_mode) {
-                self.words.push(Word::new(k.to_string()));
+            if let Some(&i) = self.index.get(k) {
+                self.words[i].add()
+            } else if Self::word_check(k, phrase) {
+                self.index.insert(k.to_vec(), self.words.len());
+                self.words.push(Word::new(k.to_vec()));
             }
         }
 
+        /// Whether the word or phrase can save anything at all, the rest is
+        /// up to `clear`.
         #[inline]
-        pub fn word_check(s: &str, windos_mode: bool) -> bool {
-            !windos_mode && s.len() >= 4 || s.len() >= 15
-        }
-
-        #[inline]
-        fn sort(&mut self) {
-            self.words.sort_by_key(|w| w.len() * w.amount());
-            self.words.reverse();
+        pub fn word_check(s: &[u8], phrase: bool) -> bool {
+            !phrase && s.len() >= 2 || s.len() >= 8
         }
 
+        /// Keeps only the words that save more than their table entry costs,
+        /// ordered as they get their codes.
+        ///
+        /// The shortest codes go first, to the words that save the most with
+        /// them, the rest is ranked again for every longer kind of code. Ties
+        /// keep the order of first appearance.
         #[inline]
         pub fn clear(&mut self) {
-            self.sort();
-
-            self.words = self
-                .words
-                .to_owned()
-                .into_iter()
-                .filter(|w| {
-                    w.word_type().is_short() && w.amount() >= 15
-                        || w.word_type().is_long() && w.amount() >= 10
-                        || w.word_type().is_verylong() && w.amount() >= 5
-                })
-                .collect_vec();
-
-            self.unused = self
-                .unused
-                .to_owned()
-                .into_iter()
-                .filter(|c| *c != b'\0' as char)
-                .collect_vec();
+            self.unused.retain(|c| !self.used.contains(c));
+
+            // Places change from here on
+            self.index = HashMap::new();
+            let mut words = std::mem::take(&mut self.words);
+
+            for (code_len, codes) in WordsMap::tiers(&self.unused) {
+                words.sort_by_key(|w| std::cmp::Reverse(w.savings(code_len)));
+                let n = words
+                    .iter()
+                    .take(codes)
+                    .take_while(|w| w.savings(code_len) > 0)
+                    .count();
+                self.words.extend(words.drain(..n));
+            }
         }
 
         #[inline]
@@ -233,7 +277,7 @@ pub mod words {
 
     #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
     pub struct Word {
-        s: String,
+        s: Vec<u8>,
         count: usize,
         word_type: WordType,
     }
@@ -247,12 +291,12 @@ pub mod words {
 
     impl Word {
         #[inline]
-        pub fn str(&self) -> &String {
+        pub fn bytes(&self) -> &[u8] {
             &self.s
         }
 
         #[inline]
-        pub fn into_str(self) -> String {
+        pub fn into_bytes(self) -> Vec<u8> {
             self.s
         }
 
@@ -266,13 +310,36 @@ pub mod words {
             self.count
         }
 
+        /// Bytes saved by swapping every use for a code of `code_len` bytes,
+        /// less its entry in the table.
+        #[inline]
+        pub fn savings(&self, code_len: usize) -> i64 {
+            self.savings_over(self.len(), code_len)
+        }
+
+        /// Same as `savings`, when a use would otherwise take `used` bytes.
+        #[inline]
+        pub fn savings_over(&self, used: usize, code_len: usize) -> i64 {
+            let len = self.len();
+            let varint = (usize::BITS - len.leading_zeros()).max(1).div_ceil(7) as usize;
+            let entry = 1 + code_len + varint + len;
+
+            (used as i64 - code_len as i64) * self.amount() as i64 - entry as i64
+        }
+
+        /// Sets how often the word is used after all.
+        #[inline]
+        pub fn set_amount(&mut self, count: usize) {
+            self.count = count
+        }
+
         #[inline]
         pub fn add(&mut self) {
             self.count += 1
         }
 
         #[inline]
-        pub fn new(s: String) -> Self {
+        pub fn new(s: Vec<u8>) -> Self {
             let len = s.len();
 
             Word {
@@ -281,11 +348,6 @@ pub mod words {
                 word_type: WordType::from(len),
             }
         }
-
-        #[inline]
-        pub fn word_type(&self) -> WordType {
-            self.word_type
-        }
     }
 
     impl WordType {
@@ -308,34 +370,51 @@ pub mod words {
                 Self::Short(u) => *u,
             }
         }
+    }
+}
 
-        #[inline]
-        pub fn is_verylong(&self) -> bool {
-            if let WordType::VeryLong(_) = self {
-                true
-            } else {
-                false
-            }
-        }
+#[test]
+fn table_cases() {
+    let words = [&b"sit amet, consectetur"[..], b"a:b", b"line\nbreak", b"\xff\x00"];
+    let m = WordsMap::from(
+        words.iter().map(|w| Word::new(w.to_vec())).collect(),
+        vec!['x', 'y', 'z', 'w', 'v'],
+    );
 
-        #[inline]
-        pub fn is_long(&self) -> bool {
-            if let WordType::Long(_) = self {
-                true
-            } else {
-                false
-            }
-        }
+    let b = m.to_bytes();
+    let back = WordsMap::from_bytes(&b).unwrap();
+    assert_eq!(back.iter().collect_vec(), m.iter().collect_vec());
+    assert_eq!(back.iter().count(), words.len());
 
-        #[inline]
-        pub fn is_short(&self) -> bool {
-            if let WordType::Short(_) = self {
-                true
-            } else {
-                false
-            }
-        }
+    // Truncated, trailing garbage, empty word
+    assert!(WordsMap::from_bytes(&b[..b.len() - 1]).is_err());
+    assert!(WordsMap::from_bytes(&[b.as_slice(), b"x"].concat()).is_err());
+    assert!(WordsMap::from_bytes(&[1, 1, b'a', 0]).is_err());
+    assert!(WordsMap::from_bytes(&[]).is_err());
+}
+
+#[test]
+fn selection_cases() {
+    use words::Words;
+
+    let mut words = Words::from(crate::alphabet::Alphabet::Letters);
+    for w in ["consectetur"; 3].iter().chain(&["word"; 2]).chain(&["of"; 9]).chain(&["ab"; 3]) {
+        words.insert(w.as_bytes(), false);
     }
+    assert_eq!(words.clone().map(|w| w.amount()).collect_vec(), vec![3, 2, 9, 3]);
+    words.clear();
+
+    // `word` and `ab` would cost more than they save
+    assert_eq!(
+        words.clone().map(|w| w.into_bytes()).collect_vec(),
+        vec![b"consectetur".to_vec(), b"of".to_vec()]
+    );
+    assert_eq!(Word::new(b"word".to_vec()).savings(1), 3 - 7);
+
+    let (w, c) = words.into_vecs();
+    assert!(!c.contains(&'o') && !c.contains(&'a'));
+    let m = WordsMap::from(w, c);
+    assert!(m.iter().all(|(ch, _)| ch.as_bytes().len() == 1));
 }
 
 #[test]
@@ -352,35 +431,39 @@ fn digraphs() {
     .map(|w| w.to_string())
     .collect_vec();
 
-    // 1 iteration
+    let codes = |w: &WoCh| {
+        let m = WordsMap::from(
+            w.words.iter().map(|w| Word::new(w.clone().into_bytes())).collect(),
+            w.chars.clone(),
+        );
+        m.iter().map(|(ch, _)| ch.as_bytes().len()).sorted().collect_vec()
+    };
+
+    // Singles only, then singles and digraphs
     let zero = WoCh {
         words: words.clone(),
         chars: chars.clone(),
     };
-    let one = WoCh {
-        words: words.clone(),
-        chars: chars[..=6].to_vec(),
-    };
     let two = WoCh {
         words: words.clone(),
         chars: chars[..=5].to_vec(),
     };
-
-    dbg!(&zero.len());
-    dbg!(&one.len());
-    dbg!(&two.len());
-
-    // right value - right side
-    assert_eq!(
-        WordsMap::amount_digraphs(zero.words.len(), zero.chars.len()),
-        0
-    );
-    assert_eq!(
-        WordsMap::amount_digraphs(one.words.len(), one.chars.len()),
-        1
-    );
-    assert_eq!(
-        WordsMap::amount_digraphs(two.words.len(), two.chars.len()),
-        2
-    );
+    assert_eq!(zero.len(), (13, 26));
+    assert_eq!(codes(&zero), vec![1; 13]);
+    assert_eq!(codes(&two), [vec![1; 6], vec![2; 7]].concat());
+
+    // Not enough codes for every word
+    let none = WoCh {
+        words,
+        chars: chars[..=2].to_vec(),
+    };
+    assert_eq!(codes(&none), [vec![1; 3], vec![2; 9]].concat());
+    assert!(codes(&WoCh { chars: vec![], ..none }).is_empty());
+
+    // Wider characters come after the shorter pairs
+    let mixed = ['a', 'b', '\u{e000}', '\u{e001}'];
+    assert_eq!(WordsMap::tiers(&mixed), vec![(1, 2), (2, 4), (3, 2), (4, 8), (6, 4)]);
+    let c = WordsMap::codes(&mixed, 9);
+    assert_eq!(c[6].to_string(), "\u{e000}");
+    assert_eq!(c[8].to_string(), "a\u{e000}");
 }
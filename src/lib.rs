@@ -1,4 +1,14 @@
-mod fs;
+mod bpe;
+mod case;
+mod format;
+mod gaps;
+mod huffman;
+mod lz77;
+mod ppm;
+pub mod alphabet;
+pub mod checksum;
+pub mod dict;
+pub mod fs;
 mod indexation;
 pub mod args;
 pub mod proc;
@@ -7,5 +17,5 @@ pub(crate) mod err;
 #[cfg(test)]
 mod tests;
 
-pub(crate) type Err = Box<dyn std::error::Error>;
-pub type Result<T> = std::result::Result<T, Err>;
+pub use err::Error;
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,168 @@
+//! LZ77 back-references in the manner of LZ4.
+//!
+//! Coded data is `length (varint) | sequence*`, where every sequence is
+//! `token | literals | offset (varint)` with the amount of literals in the
+//! high nibble of the token and the match length less `MIN_MATCH` in the low
+//! one. A nibble of 15 is followed by a varint with the rest. The last
+//! sequence ends after its literals.
+
+use crate::{
+    err::Error,
+    format::{push_varint, Reader},
+    Result,
+};
+
+const MIN_MATCH: usize = 4;
+/// Candidates looked at for every position.
+const DEPTH: usize = 16;
+const HASH_BITS: u32 = 16;
+
+const NONE: u32 = u32::MAX;
+
+#[inline]
+fn hash(b: &[u8]) -> usize {
+    let v = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+#[inline]
+fn push_len(b: &mut Vec<u8>, extra: usize) {
+    if extra >= 15 {
+        push_varint(b, (extra - 15) as u64)
+    }
+}
+
+#[inline]
+pub fn encode(b: &[u8]) -> Vec<u8> {
+    let mut r = Vec::with_capacity(b.len() / 2 + 8);
+    push_varint(&mut r, b.len() as u64);
+
+    // The last position of every hash and the one before every position
+    let mut head = vec![NONE; 1 << HASH_BITS];
+    let mut prev = vec![NONE; b.len()];
+
+    let (mut i, mut literals) = (0, 0);
+    while i + MIN_MATCH <= b.len() {
+        let h = hash(&b[i..]);
+
+        let (mut best, mut offset) = (0, 0);
+        let mut candidate = head[h];
+        for _ in 0..DEPTH {
+            if candidate == NONE {
+                break;
+            }
+            let c = candidate as usize;
+            let len = b[c..].iter().zip(&b[i..]).take_while(|(x, y)| x == y).count();
+            if len > best {
+                (best, offset) = (len, i - c);
+            }
+            candidate = prev[c];
+        }
+
+        prev[i] = head[h];
+        head[h] = i as u32;
+
+        if best < MIN_MATCH {
+            i += 1;
+            continue;
+        }
+
+        let lit = i - literals;
+        let extra = best - MIN_MATCH;
+        r.push((lit.min(15) << 4 | extra.min(15)) as u8);
+        push_len(&mut r, lit);
+        r.extend_from_slice(&b[literals..i]);
+        push_varint(&mut r, offset as u64);
+        push_len(&mut r, extra);
+
+        // Positions inside the match are still worth finding later on
+        for j in i + 1..(i + best).min(b.len() - MIN_MATCH + 1) {
+            let h = hash(&b[j..]);
+            prev[j] = head[h];
+            head[h] = j as u32;
+        }
+        i += best;
+        literals = i;
+    }
+
+    let lit = b.len() - literals;
+    r.push((lit.min(15) << 4) as u8);
+    push_len(&mut r, lit);
+    r.extend_from_slice(&b[literals..]);
+    r
+}
+
+/// Reverses `encode`, the data must not come to more than `max` bytes.
+#[inline]
+pub fn decode(b: &[u8], max: usize) -> Result<Vec<u8>> {
+    let corrupted = || Error::corrupted("Malformed back-references.");
+
+    let mut r = Reader::from(b);
+    let n = r.varint()? as usize;
+    if n > max {
+        return Err(corrupted());
+    }
+
+    let len = |r: &mut Reader<&[u8]>, nibble: u8| -> Result<usize> {
+        Ok(match nibble {
+            15 => 15 + r.varint()? as usize,
+            l => l as usize,
+        })
+    };
+
+    let mut out = Vec::new();
+    loop {
+        let token = r.u8()?;
+
+        let lit = len(&mut r, token >> 4)?;
+        if out.len() + lit > n {
+            return Err(corrupted());
+        }
+        out.extend_from_slice(&r.bytes(lit)?);
+        if out.len() == n {
+            break;
+        }
+
+        let offset = r.varint()? as usize;
+        let matched = len(&mut r, token & 0xf)? + MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + matched > n {
+            return Err(corrupted());
+        }
+        // Byte by byte, a match may overlap what it copies
+        let start = out.len() - offset;
+        for k in 0..matched {
+            out.push(out[start + k]);
+        }
+    }
+
+    if !r.at_end()? {
+        return Err(corrupted());
+    }
+    Ok(out)
+}
+
+#[test]
+fn lz77_cases() {
+    let text = std::fs::read("short").unwrap();
+    let log = (0..200)
+        .map(|i| format!("2024-01-01T00:00:{:02} server[42]: request id={i} done\n", i % 60))
+        .collect::<String>();
+
+    let runs = [&b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"[..], &[0; 70000]];
+    for b in [&text[..], log.as_bytes(), b"", b"abc"].into_iter().chain(runs) {
+        let coded = encode(b);
+        assert_eq!(decode(&coded, b.len()).unwrap(), b);
+    }
+    assert!(encode(log.as_bytes()).len() < log.len() / 4);
+
+    // Too long, truncated and an offset before the start
+    let coded = encode(log.as_bytes());
+    assert!(decode(&coded, log.len() - 1).is_err());
+    assert!(decode(&coded[..coded.len() - 1], log.len()).is_err());
+    assert!(decode(&[8, 0x10, b'a', 2], 8).is_err());
+
+    // A length far beyond the data
+    let mut huge = Vec::new();
+    push_varint(&mut huge, u32::MAX as u64);
+    assert!(decode(&huge, usize::MAX).is_err());
+}
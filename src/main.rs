@@ -1,31 +1,91 @@
 use wordzip::{
     args::{Args, Mode},
-    proc::{Do, Unzip, Zip},
-    Result,
+    dict::Dict,
+    fs::{self, Input},
+    proc::{Do, Options, Stats, Train, Unzip, Zip},
+    Error, Result,
 };
 
 use std::env::args;
-use std::{fs::File, io};
+use std::io::{self, IsTerminal, Read, Write};
+use std::process;
 
-fn main() -> Result<()> {
-    let args = args().into_iter().collect::<Vec<_>>();
+const PROGRAM_NAME: &str = "wordzip";
 
-    let args = Args::parse(&args)?;
+/// Exits with 0 on success, otherwise with the code of the error kind:
+/// 1 io, 2 invalid arguments, 3 output exists, 4 empty input, 5 corrupted
+/// archive, 6 unsupported version, 7 checksum mismatch and 8 decompressed
+/// data mismatch.
+fn main() {
+    let args = args().collect::<Vec<_>>();
 
-    run(args)?;
-
-    Ok(())
+    if let Err(e) = Args::parse(&args).and_then(run) {
+        eprintln!("{PROGRAM_NAME}: {e}");
+        process::exit(e.exit_code());
+    }
 }
 
 fn run(a: Args) -> Result<()> {
-    let f = File::open(a.input_file)?;
-    let s = io::read_to_string(f)?;
+    if a.mode != Mode::Unzip && a.output_file.is_none() && io::stdout().is_terminal() && !a.force {
+        return Err(Error::invalid_args(
+            "Refusing to write compressed data to a terminal!\n\
+                    Specify output file or use `-f` to force it.",
+        ));
+    }
+
+    // Creating the output would truncate the input before it is read
+    if let (Some(i), Some(o)) = (a.input_file(), a.output_file()) {
+        if fs::same_file(i, o) {
+            return Err(Error::invalid_args("The input and output are the same file!"));
+        }
+    }
+
+    let dict = match a.dict() {
+        Some(p) => Some(Dict::read(fs::open(Some(p))?)?),
+        None => None,
+    };
+    let input = fs::input(a.input_file())?;
+    let mut output = fs::create(a.output_file())?;
+
+    let options = Options {
+        block_size: a.block_size,
+        checksum: a.checksum,
+        huffman: a.huffman,
+        alphabet: a.alphabet,
+        max_ngram: a.max_ngram,
+        threads: a.threads,
+        method: a.method,
+        lz77: a.lz77,
+        fold_case: a.fold_case,
+        gaps: a.gaps,
+    };
+
+    // Archives are read as a stream, out of the map when there is one
+    let unzip = |input: Input<_>| {
+        Unzip::<Box<dyn Read>>::from(Box::new(input)).with_threads(a.threads)
+    };
 
-    let to_save = match a.mode {
-        Mode::Zip => Do::Zip(Zip::from(s)).proc(),
-        Mode::Unzip => Do::Unzip(Unzip::from(s)).proc(),
+    let to_do = match (a.mode, dict) {
+        (Mode::Zip, Some(d)) => Do::Zip(Zip::from_input(input, options)?.with_dict(d)),
+        (Mode::Zip, None) => Do::Zip(Zip::from_input(input, options)?),
+        (Mode::Unzip, Some(d)) => Do::Unzip(unzip(input).with_dict(d)),
+        (Mode::Unzip, None) => Do::Unzip(unzip(input)),
+        (Mode::Train, _) => Do::Train(Train::from_input(input, options)),
     };
-    to_save?.save(a.output_file)?;
+    let r = to_do.proc(&mut output).and_then(|stats| {
+        output.flush()?;
+        Ok(stats)
+    });
+
+    // Do not leave a half-written file behind
+    if let (Err(_), Some(p)) = (&r, a.output_file()) {
+        drop(output);
+        let _ = std::fs::remove_file(p);
+    }
+
+    if a.mode == Mode::Zip && r.as_ref().is_ok_and(Stats::nothing_gained) {
+        eprintln!("{PROGRAM_NAME}: no compression was gained, the input is stored as is");
+    }
 
-    Ok(())
+    r.map(drop)
 }
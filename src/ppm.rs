@@ -0,0 +1,480 @@
+//! Adaptive context modelling of tokens with arithmetic coding.
+//!
+//! Every token is predicted from the two tokens before it, then from the one
+//! before it, then from none, escaping to the next shorter context when it was
+//! never seen in the longer one. A token that was never seen at all is the
+//! next one of the vocabulary, which lists the tokens in order of appearance
+//! and is stored as `tokens (varint) | (length (varint) | token)*`. The data
+//! are the amount of tokens (varint) followed by the arithmetic code.
+
+use crate::{
+    err::Error,
+    format::{push_varint, Reader},
+    Result,
+};
+use std::collections::HashMap;
+
+/// Counts of a context are halved when their total gets past this.
+const MAX_TOTAL: u32 = 1 << 24;
+/// Larger vocabularies can not be counted within `MAX_TOTAL`.
+pub const MAX_TOKENS: usize = 1 << 22;
+
+const HALF: u64 = 1 << 31;
+const QUARTER: u64 = 1 << 30;
+const TOP: u64 = (1 << 32) - 1;
+
+#[derive(Debug, PartialEq)]
+pub struct Ppm {
+    tokens: Vec<Vec<u8>>,
+}
+
+impl Ppm {
+    /// The vocabulary of `s` and the ids of its tokens.
+    #[inline]
+    pub fn from(s: &[&[u8]]) -> (Self, Vec<u32>) {
+        let mut ids: HashMap<&[u8], u32> = HashMap::new();
+        let mut tokens = Vec::new();
+
+        let seq = s
+            .iter()
+            .map(|&t| {
+                *ids.entry(t).or_insert_with(|| {
+                    tokens.push(t.to_vec());
+                    tokens.len() as u32 - 1
+                })
+            })
+            .collect();
+
+        (Ppm { tokens }, seq)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    #[inline]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+        push_varint(&mut b, self.tokens.len() as u64);
+        for t in &self.tokens {
+            push_varint(&mut b, t.len() as u64);
+            b.extend_from_slice(t);
+        }
+        b
+    }
+
+    #[inline]
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        let corrupted = || Error::corrupted("Malformed vocabulary.");
+
+        let mut r = Reader::from(b);
+        let mut tokens = Vec::new();
+
+        for _ in 0..r.varint()? {
+            let n = r.varint()? as usize;
+            let t = r.bytes(n)?;
+            if t.is_empty() || tokens.len() == MAX_TOKENS {
+                return Err(corrupted());
+            }
+            tokens.push(t);
+        }
+
+        if !r.at_end()? {
+            return Err(corrupted());
+        }
+        Ok(Ppm { tokens })
+    }
+
+    #[inline]
+    pub fn encode(seq: &[u32]) -> Vec<u8> {
+        let mut b = Vec::new();
+        push_varint(&mut b, seq.len() as u64);
+
+        let mut model = Model::new();
+        let mut coder = Encoder::new(b);
+
+        for &t in seq {
+            for key in model.keys() {
+                let Some(f) = model.contexts.get(&key) else { continue };
+
+                let total = f.total + f.escape();
+                match f.index.get(&t) {
+                    Some(&i) => {
+                        let low = f.prefix(i);
+                        coder.encode(low, low + f.count(i), total);
+                        break;
+                    }
+                    None => coder.encode(f.total, total, total),
+                }
+            }
+            model.update(t);
+        }
+        coder.finish()
+    }
+
+    /// Expands the data of `b`, which must not come to more than `size` bytes.
+    #[inline]
+    pub fn decode(&self, b: &[u8], size: usize) -> Result<Vec<u8>> {
+        let corrupted = || Error::corrupted("Malformed token data.");
+
+        let mut r = Reader::from(b);
+        let n = r.varint()? as usize;
+        if n > size {
+            return Err(corrupted());
+        }
+
+        let mut model = Model::new();
+        let mut coder = Decoder::new(r.into_inner());
+        let mut out = Vec::new();
+
+        for _ in 0..n {
+            let mut found = None;
+
+            for key in model.keys() {
+                let Some(f) = model.contexts.get(&key) else { continue };
+
+                let total = f.total + f.escape();
+                let target = coder.target(total);
+                if target < f.total {
+                    let i = f.find(target);
+                    let low = f.prefix(i);
+                    coder.update(low, low + f.count(i), total);
+                    found = Some(f.syms[i]);
+                    break;
+                }
+                coder.update(f.total, total, total);
+            }
+
+            // Never seen, so it is the next new one
+            let t = found.unwrap_or(model.seen);
+            let token = self.tokens.get(t as usize).ok_or_else(corrupted)?;
+            out.extend_from_slice(token);
+            if out.len() > size {
+                return Err(corrupted());
+            }
+            model.update(t);
+        }
+        Ok(out)
+    }
+}
+
+/// Token counts of the contexts of order 2, 1 and 0.
+struct Model {
+    contexts: HashMap<(u8, u32, u32), Freq>,
+    history: [u32; 2],
+    seen: u32,
+}
+
+impl Model {
+    #[inline]
+    fn new() -> Self {
+        Model {
+            contexts: HashMap::new(),
+            history: [u32::MAX; 2],
+            seen: 0,
+        }
+    }
+
+    /// Longest context first.
+    #[inline]
+    fn keys(&self) -> [(u8, u32, u32); 3] {
+        let [a, b] = self.history;
+        [(2, a, b), (1, 0, b), (0, 0, 0)]
+    }
+
+    #[inline]
+    fn update(&mut self, t: u32) {
+        for key in self.keys() {
+            self.contexts.entry(key).or_insert_with(Freq::new).add(t);
+        }
+        self.seen = self.seen.max(t + 1);
+        self.history = [self.history[1], t];
+    }
+}
+
+/// Counts of the tokens seen in a context, as a Fenwick tree in the order
+/// they were first seen there.
+struct Freq {
+    syms: Vec<u32>,
+    counts: Vec<u32>,
+    tree: Vec<u32>,
+    index: HashMap<u32, usize>,
+    total: u32,
+}
+
+impl Freq {
+    #[inline]
+    fn new() -> Self {
+        Freq {
+            syms: Vec::new(),
+            counts: Vec::new(),
+            tree: vec![0],
+            index: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    /// Method C: as likely as the amount of tokens seen.
+    #[inline]
+    fn escape(&self) -> u32 {
+        self.syms.len() as u32
+    }
+
+    #[inline]
+    fn count(&self, i: usize) -> u32 {
+        self.counts[i]
+    }
+
+    /// Sum of the counts before `i`.
+    #[inline]
+    fn prefix(&self, i: usize) -> u32 {
+        let (mut k, mut sum) = (i, 0);
+        while k > 0 {
+            sum += self.tree[k];
+            k &= k - 1;
+        }
+        sum
+    }
+
+    /// The token whose counts cover `target`, which is below the total.
+    #[inline]
+    fn find(&self, mut target: u32) -> usize {
+        let n = self.syms.len();
+        let (mut pos, mut step) = (0, n.checked_next_power_of_two().unwrap_or(0));
+
+        while step > 0 {
+            if pos + step <= n && self.tree[pos + step] <= target {
+                pos += step;
+                target -= self.tree[pos];
+            }
+            step >>= 1;
+        }
+        pos
+    }
+
+    #[inline]
+    fn add(&mut self, t: u32) {
+        match self.index.get(&t) {
+            Some(&i) => {
+                self.counts[i] += 1;
+                let mut k = i + 1;
+                while k < self.tree.len() {
+                    self.tree[k] += 1;
+                    k += k & k.wrapping_neg();
+                }
+            }
+            None => {
+                let k = self.tree.len();
+                self.index.insert(t, self.syms.len());
+                self.syms.push(t);
+                self.counts.push(1);
+                // A new last node covers the counts just before it as well
+                let node = 1 + self.prefix(k - 1) - self.prefix(k - (k & k.wrapping_neg()));
+                self.tree.push(node);
+            }
+        }
+        self.total += 1;
+
+        if self.total + self.escape() > MAX_TOTAL {
+            self.counts.iter_mut().for_each(|c| *c = c.div_ceil(2));
+            self.rebuild();
+        }
+    }
+
+    #[inline]
+    fn rebuild(&mut self) {
+        self.tree = vec![0; self.counts.len() + 1];
+        for (i, &c) in self.counts.iter().enumerate() {
+            let k = i + 1;
+            self.tree[k] += c;
+            let parent = k + (k & k.wrapping_neg());
+            if parent < self.tree.len() {
+                self.tree[parent] += self.tree[k];
+            }
+        }
+        self.total = self.counts.iter().sum();
+    }
+}
+
+/// Arithmetic coder on 32 bits, in the manner of Witten, Neal and Cleary.
+struct Encoder {
+    out: Vec<u8>,
+    low: u64,
+    high: u64,
+    pending: u32,
+    byte: u8,
+    bits: u32,
+}
+
+impl Encoder {
+    #[inline]
+    fn new(out: Vec<u8>) -> Self {
+        Encoder {
+            out,
+            low: 0,
+            high: TOP,
+            pending: 0,
+            byte: 0,
+            bits: 0,
+        }
+    }
+
+    #[inline]
+    fn encode(&mut self, low: u32, high: u32, total: u32) {
+        let range = self.high - self.low + 1;
+        self.high = self.low + range * high as u64 / total as u64 - 1;
+        self.low += range * low as u64 / total as u64;
+
+        loop {
+            if self.high < HALF {
+                self.bit(0);
+            } else if self.low >= HALF {
+                self.bit(1);
+                self.low -= HALF;
+                self.high -= HALF;
+            } else if self.low >= QUARTER && self.high < HALF + QUARTER {
+                self.pending += 1;
+                self.low -= QUARTER;
+                self.high -= QUARTER;
+            } else {
+                break;
+            }
+            self.low <<= 1;
+            self.high = self.high << 1 | 1;
+        }
+    }
+
+    #[inline]
+    fn bit(&mut self, b: u8) {
+        self.push(b);
+        for _ in 0..self.pending {
+            self.push(b ^ 1);
+        }
+        self.pending = 0;
+    }
+
+    #[inline]
+    fn push(&mut self, b: u8) {
+        self.byte = self.byte << 1 | b;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.out.push(self.byte);
+            (self.byte, self.bits) = (0, 0);
+        }
+    }
+
+    #[inline]
+    fn finish(mut self) -> Vec<u8> {
+        self.pending += 1;
+        self.bit((self.low >= QUARTER) as u8);
+        while self.bits > 0 {
+            self.push(0);
+        }
+        self.out
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    low: u64,
+    high: u64,
+    value: u64,
+}
+
+impl<'a> Decoder<'a> {
+    #[inline]
+    fn new(data: &'a [u8]) -> Self {
+        let mut d = Decoder {
+            data,
+            pos: 0,
+            low: 0,
+            high: TOP,
+            value: 0,
+        };
+        for _ in 0..32 {
+            d.value = d.value << 1 | d.next() as u64;
+        }
+        d
+    }
+
+    /// Bits past the end are zeros.
+    #[inline]
+    fn next(&mut self) -> u8 {
+        let b = self.data.get(self.pos / 8).map_or(0, |b| b >> (7 - self.pos % 8) & 1);
+        self.pos += 1;
+        b
+    }
+
+    #[inline]
+    fn target(&self, total: u32) -> u32 {
+        let range = self.high - self.low + 1;
+        (((self.value - self.low + 1) * total as u64 - 1) / range) as u32
+    }
+
+    #[inline]
+    fn update(&mut self, low: u32, high: u32, total: u32) {
+        let range = self.high - self.low + 1;
+        self.high = self.low + range * high as u64 / total as u64 - 1;
+        self.low += range * low as u64 / total as u64;
+
+        loop {
+            let shift = if self.high < HALF {
+                0
+            } else if self.low >= HALF {
+                HALF
+            } else if self.low >= QUARTER && self.high < HALF + QUARTER {
+                QUARTER
+            } else {
+                break;
+            };
+            self.low -= shift;
+            self.high -= shift;
+            self.value -= shift;
+            self.low <<= 1;
+            self.high = self.high << 1 | 1;
+            self.value = self.value << 1 | self.next() as u64;
+        }
+    }
+}
+
+#[test]
+fn ppm_cases() {
+    let text = std::fs::read("short").unwrap().repeat(3);
+    let tokens = text.split_inclusive(|&b| b == b' ').collect::<Vec<_>>();
+
+    let (ppm, seq) = Ppm::from(&tokens);
+    let data = Ppm::encode(&seq);
+    // Repeats are all but free once they are known
+    assert!(data.len() < text.len() / 6);
+
+    let back = Ppm::from_bytes(&ppm.to_bytes()).unwrap();
+    assert_eq!(back, ppm);
+    assert_eq!(back.decode(&data, text.len()).unwrap(), text);
+    assert_eq!(back.decode(&data, usize::MAX).unwrap(), text);
+
+    for s in [&[][..], &[&b"a"[..]], &[&b"a"[..]; 1000], &[&b"x"[..], b"y", b"x", b"z"]] {
+        let (ppm, seq) = Ppm::from(s);
+        assert_eq!(ppm.decode(&Ppm::encode(&seq), s.concat().len()).unwrap(), s.concat());
+    }
+
+    // Too much data and an empty token
+    assert!(ppm.decode(&data, text.len() - 1).is_err());
+    assert!(Ppm::from_bytes(&[1, 0]).is_err());
+}
+
+#[test]
+fn freq_cases() {
+    let mut f = Freq::new();
+    for t in [5, 3, 5, 9, 5, 3] {
+        f.add(t);
+    }
+    assert_eq!((f.total, f.escape()), (6, 3));
+    assert_eq!((f.prefix(0), f.prefix(1), f.prefix(2), f.prefix(3)), (0, 3, 5, 6));
+    assert_eq!([0, 2, 3, 4, 5].map(|t| f.find(t)), [0, 0, 1, 1, 2]);
+
+    f.counts = vec![7, 1, 2];
+    f.rebuild();
+    assert_eq!((f.total, f.prefix(1), f.find(7)), (10, 7, 1));
+}
@@ -1,140 +1,598 @@
-const SEP: &'static str = "\u{2ffff}";
+/// Marks the next alphabetic token as literal; a doubled one stands for itself.
+const ESC: u8 = 0x1b;
+/// Passes over a block that drop a kind of failing entries at a time. The
+/// last one drops all of them and ends the pruning, so a block is
+/// substituted at most once more than this.
+const PRUNE_PASSES: usize = 8;
+/// Largest block, as a block records its size in a `u32`.
+const MAX_BLOCK_SIZE: usize = u32::MAX as usize;
 
 use itertools::Itertools;
-use sha256::digest;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
 
 use crate::{
+    alphabet::Alphabet,
+    checksum::Checksum,
+    dict::Dict,
     err::Error,
-    fs::ToSave,
-    indexation::{words::Words, WordsMap},
+    bpe::Bpe,
+    case,
+    format::{Block, Header, Reader, BPE, CASE, DICT, GAPS, HUFFMAN, LZ77, PPM, STORED},
+    fs::{Blocks, Input},
+    gaps,
+    huffman::Huffman,
+    indexation::{
+        words::{Word, Words},
+        WordsMap,
+    },
+    lz77,
+    ppm::{self, Ppm},
     Result,
 };
 
-pub enum Do {
-    Zip(Zip),     // struct inside
-    Unzip(Unzip), // struct inside
+pub enum Do<R: Read> {
+    Zip(Zip<R>),     // struct inside
+    Unzip(Unzip<R>), // struct inside
+    Train(Train<R>), // struct inside
 }
 
-pub struct Zip {
-    original: String,
+pub struct Zip<R: Read> {
+    original: Blocks<R>,
+    options: Options,
+    dict: Option<Dict>,
 }
 
-pub struct Unzip {
-    compressed: String,
+/// How `Zip` compresses, everything `Unzip` needs is recorded in the archive.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Options {
+    pub block_size: usize,
+    pub checksum: Checksum,
+    pub huffman: bool,
+    pub alphabet: Alphabet,
+    pub method: Method,
+    /// Back-references after the method, see `lz77`.
+    pub lz77: bool,
+    /// Longest phrase in words that gets a code of its own.
+    pub max_ngram: usize,
+    /// Blocks coded at once, one per core for 0.
+    pub threads: usize,
+    /// Capitalized words share the entry of their lowercase form, see `case`.
+    pub fold_case: bool,
+    /// Frequent separators between words get a byte of their own, see `gaps`.
+    pub gaps: bool,
 }
 
-impl Do {
+/// How the tokens of a block are coded, recorded in the flags of every block.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Method {
+    /// Frequent words and phrases are swapped for short codes.
+    Words,
+    /// Tokens become symbols that are merged pairwise, see `Bpe`.
+    Bpe,
+    /// Tokens are predicted from the ones before and arithmetic coded, see
+    /// `Ppm`. The best ratio, at the lowest speed.
+    Ppm,
+    /// Tokens are left as they are, for the stages after.
+    None,
+}
+
+pub struct Unzip<R: Read> {
+    compressed: Reader<R>,
+    dict: Option<Dict>,
+    threads: usize,
+}
+
+/// Makes a shared `Dict` out of a sample.
+pub struct Train<R: Read> {
+    sample: Input<R>,
+    options: Options,
+}
+
+/// What was done to the blocks.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Stats {
+    pub blocks: usize,
+    pub stored: usize,
+}
+
+impl Stats {
+    /// Whether there were blocks, but none was made any smaller.
     #[inline]
-    pub fn proc(self) -> Result<ToSave> {
-        match self {
-            Self::Zip(z) => z.proc(),
-            Self::Unzip(u) => u.proc(),
+    pub fn nothing_gained(&self) -> bool {
+        self.blocks > 0 && self.blocks == self.stored
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            block_size: 1 << 20,
+            checksum: Checksum::Crc32,
+            huffman: false,
+            alphabet: Alphabet::Letters,
+            method: Method::Words,
+            lz77: false,
+            max_ngram: 3,
+            threads: 0,
+            fold_case: false,
+            gaps: false,
         }
     }
 }
 
-impl Zip {
+impl Options {
+    /// Block flags of the pipeline, recorded in the header.
     #[inline]
-    pub fn from(original: String) -> Zip {
-        Zip { original }
+    fn stages(&self) -> u8 {
+        let mut flags = 0;
+        match self.method {
+            Method::Bpe => flags |= BPE,
+            Method::Ppm => flags |= PPM,
+            Method::Words | Method::None => (),
+        }
+        if self.fold_case {
+            flags |= CASE
+        }
+        if self.gaps {
+            flags |= GAPS
+        }
+        if self.lz77 {
+            flags |= LZ77
+        }
+        if self.huffman {
+            flags |= HUFFMAN
+        }
+        flags
     }
+}
+
+impl FromStr for Method {
+    type Err = Error;
 
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "words" => Ok(Self::Words),
+            "bpe" => Ok(Self::Bpe),
+            "ppm" => Ok(Self::Ppm),
+            "none" => Ok(Self::None),
+            _ => Err(Error::invalid_args("Expected words, bpe, ppm or none method!")),
+        }
+    }
+}
+
+impl<R: Read> Do<R> {
     #[inline]
-    pub fn proc(self) -> Result<ToSave> {
-        let mut s = split(&self.original);
+    pub fn proc<W: Write>(self, out: &mut W) -> Result<Stats> {
+        match self {
+            Self::Zip(z) => z.proc(out),
+            Self::Unzip(u) => u.proc(out),
+            Self::Train(t) => t.proc(out),
+        }
+    }
+}
 
-        let mut words = Words::new();
+impl<R: Read> Zip<R> {
+    #[inline]
+    pub fn from(original: R, options: Options) -> Result<Zip<R>> {
+        Self::from_input(Input::Buffered(original), options)
+    }
 
-        s.windows(3).map(|w| words.insert(&w.join(""), true)).collect_vec();
-        s.iter().map(|w| words.insert(w, false)).collect_vec();
-        words.clear();
+    /// Compresses a mapped input without copying its blocks.
+    #[inline]
+    pub fn from_input(original: Input<R>, options: Options) -> Result<Zip<R>> {
+        Ok(Zip {
+            original: Blocks::from(original, check_block_size(options.block_size)?),
+            options,
+            dict: None,
+        })
+    }
 
-        if words.is_empty() {
-            return Err(Box::new(Error::new("file", "Nothing to compress!")));
-        }
+    /// Codes words by a shared dictionary, whose alphabet takes the place of
+    /// the one of the options.
+    #[inline]
+    pub fn with_dict(mut self, dict: Dict) -> Self {
+        self.dict = Some(dict);
+        self
+    }
 
-        let (w, c) = words.into_vecs();
-        let m = WordsMap::from(w, c);
+    /// Compresses as many blocks at once as there are threads, so memory use
+    /// is bounded by the block size times the threads. Every block is coded
+    /// on its own, the archive is the same for any amount of threads.
+    ///
+    /// Blocks that would not shrink are stored, so any input makes an archive.
+    #[inline]
+    pub fn proc<W: Write>(mut self, out: &mut W) -> Result<Stats> {
+        let header = Header {
+            flags: self.options.stages() | self.dict.as_ref().map_or(0, |_| DICT),
+            checksum: self.options.checksum,
+            alphabet: self.dict.as_ref().map_or(self.options.alphabet, Dict::alphabet),
+            dict: self.dict.as_ref().map(Dict::id),
+        };
+        header.write(out)?;
+        let shared = self.dict.as_ref().map(Dict::map);
+        let pool = pool(self.options.threads)?;
 
-        let mut table = String::new();
+        let (mut total, mut stats) = (0u64, Stats::default());
 
-        for (ch, word) in m.iter() {
-            table.push_str(&format!("{}:{}\n", ch, word));
-            s.iter_mut()
-                .map(|w| {
-                    if w == word {
-                        *w = ch.as_str().to_owned()
-                    }
-                })
-                .for_each(drop);
-        }
+        loop {
+            let batch = self.original.batch(pool.current_num_threads())?;
+            if batch.is_empty() {
+                break;
+            }
 
-        let content = s.join("");
-        let hash = digest(format!("{table}{content}"));
+            let o = &self.options;
+            let blocks = pool.install(|| {
+                batch.par_iter().map(|b| zip_block(b, &header, o, shared)).collect::<Vec<_>>()
+            });
+            for block in blocks {
+                total += block.size as u64;
+                stats.blocks += 1;
+                if block.flags == STORED {
+                    stats.stored += 1
+                }
+                block.write(out)?;
+            }
+        }
 
-        Ok(ToSave::new(format!("{hash}{SEP}{table}{SEP}{content}")))
+        Block::write_end(out, total)?;
+        Ok(stats)
     }
 }
 
-impl Unzip {
+impl<R: Read> Unzip<R> {
     #[inline]
-    pub fn from(original: String) -> Unzip {
+    pub fn from(original: R) -> Unzip<R> {
         Unzip {
-            compressed: original,
+            compressed: Reader::from(original),
+            dict: None,
+            threads: 0,
         }
     }
 
+    /// Blocks decoded at once, one per core for 0.
     #[inline]
-    pub fn proc(self) -> Result<ToSave> {
-        // return Err(Box::new(Error::new("file", "File is corrupted!")))
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
 
-        let l: Option<(&str, &str, &str)> = self.compressed.split(SEP).collect_tuple();
+    /// The shared dictionary, for archives made with it.
+    #[inline]
+    pub fn with_dict(mut self, dict: Dict) -> Self {
+        self.dict = Some(dict);
+        self
+    }
 
-        let (plain, content): (String, String);
+    #[inline]
+    pub fn proc<W: Write>(mut self, out: &mut W) -> Result<Stats> {
+        let header = Header::read(&mut self.compressed)?;
 
-        if let Some((hash, imap, icontent)) = l {
-            plain = imap.to_string();
-            content = icontent.to_string();
+        let shared = match (header.dict, &self.dict) {
+            (None, _) => None,
+            (Some(id), Some(d)) if d.id() == id => Some(d.map()),
+            (Some(id), _) => {
+                return Err(Error::invalid_args(&format!(
+                    "The archive needs the dictionary {id:016x}, give it with `--dict`!"
+                )))
+            }
+        };
+
+        let pool = pool(self.threads)?;
 
-            if digest(format!("{}{}", &plain, &content)) != hash {
-                return Err(Box::new(Error::new("file", "File is corrupted!")));
+        let (mut total, mut stats) = (0u64, Stats::default());
+
+        let mut end = false;
+        while !end {
+            let mut batch = Vec::with_capacity(pool.current_num_threads());
+            while batch.len() < pool.current_num_threads() {
+                let Some(block) = Block::read(&mut self.compressed, &header)? else {
+                    end = true;
+                    break;
+                };
+                stats.blocks += 1;
+                if block.flags == STORED {
+                    stats.stored += 1
+                }
+                batch.push(block);
             }
-        } else {
-            return Err(Box::new(Error::new("file", "File is corrupted!")));
+
+            let h = &header;
+            let blocks = pool.install(|| {
+                batch.into_par_iter().map(|b| unzip_block(b, h, shared)).collect::<Vec<_>>()
+            });
+            // The first error in the order of the blocks, as when decoding one by one
+            for b in blocks {
+                let b = b?;
+                total += b.len() as u64;
+                out.write_all(&b)?;
+            }
+        }
+
+        // Sections are framed by their lengths only, so anything after the
+        // trailer can not belong to this archive
+        if self.compressed.u64()? != total || !self.compressed.at_end()? {
+            return Err(Error::corrupted("Wrong total size or trailing data."));
+        }
+
+        Ok(stats)
+    }
+}
+
+impl<R: Read> Train<R> {
+    #[inline]
+    pub fn from(sample: R, options: Options) -> Train<R> {
+        Self::from_input(Input::Buffered(sample), options)
+    }
+
+    /// Trains on a mapped sample without copying it.
+    #[inline]
+    pub fn from_input(sample: Input<R>, options: Options) -> Train<R> {
+        Train { sample, options }
+    }
+
+    /// Reads the whole sample, a dictionary is not made block by block. The
+    /// sample is folded and its gaps coded like the blocks it is meant for.
+    #[inline]
+    pub fn proc<W: Write>(mut self, out: &mut W) -> Result<Stats> {
+        let mut read = Vec::new();
+        let sample = match &mut self.sample {
+            Input::Mapped(c) => &c.get_ref()[c.position() as usize..],
+            Input::Buffered(r) => {
+                r.read_to_end(&mut read)?;
+                &read[..]
+            }
+        };
+
+        let (_, sample) = transform(sample, &self.options, self.options.alphabet);
+        let dict = Dict::train(&sample, self.options.alphabet, self.options.max_ngram)?;
+        out.write_all(&dict.to_bytes())?;
+        Ok(Stats::default())
+    }
+}
+
+/// Gives back `size` if it is between 1 KiB and `MAX_BLOCK_SIZE`.
+#[inline]
+pub(crate) fn check_block_size(size: usize) -> Result<usize> {
+    match size {
+        1024..=MAX_BLOCK_SIZE => Ok(size),
+        _ => Err(Error::invalid_args("Block size must be between 1 KiB and 4 GiB!")),
+    }
+}
+
+/// Threads for coding blocks, one per core for 0.
+#[inline]
+fn pool(threads: usize) -> Result<ThreadPool> {
+    let pool = ThreadPoolBuilder::new().num_threads(threads).build();
+    pool.map_err(|e| Error::Io(io::Error::other(e)))
+}
+
+/// Folds the case and codes the gaps of a text as asked, with the flags of
+/// what was done.
+#[inline]
+fn transform<'a>(b: &'a [u8], o: &Options, alphabet: Alphabet) -> (u8, Cow<'a, [u8]>) {
+    let (mut flags, mut text) = (0, Cow::Borrowed(b));
+    if o.fold_case {
+        (flags, text) = (flags | CASE, Cow::Owned(case::fold(&text, alphabet)));
+    }
+    if o.gaps {
+        (flags, text) = (flags | GAPS, Cow::Owned(gaps::encode(&text, alphabet)));
+    }
+    (flags, text)
+}
+
+/// Codes a single block with the chosen method, or stores it when that does
+/// not make it smaller. Either then goes through the other stages that are
+/// asked for and pay off.
+#[inline]
+fn zip_block(b: &[u8], h: &Header, o: &Options, shared: Option<&WordsMap>) -> Block {
+    // Transforms of the text that only pay off with a method after them
+    let (pre, text) = match o.method {
+        Method::None => (0, Cow::Borrowed(b)),
+        _ => transform(b, o, h.alphabet),
+    };
+    let tokens = split(&text, h.alphabet);
+
+    let coded = match o.method {
+        Method::Words => match shared {
+            Some(m) => Some((pre | DICT, Vec::new(), substitute(&tokens, m, h.alphabet).0)),
+            None => words_block(&tokens, h.alphabet, o.max_ngram)
+                .map(|(m, p)| (pre, m.to_bytes(), p)),
+        },
+        Method::Bpe => {
+            let (bpe, seq) = Bpe::from(&tokens);
+            Some((pre | BPE, bpe.to_bytes(), Bpe::encode(&seq)))
+        }
+        Method::Ppm => {
+            let (ppm, seq) = Ppm::from(&tokens);
+            (ppm.len() <= ppm::MAX_TOKENS)
+                .then(|| (pre | PPM, ppm.to_bytes(), Ppm::encode(&seq)))
+        }
+        Method::None => None,
+    };
+
+    // Borrowed until a stage pays off, a block is only copied to be stored
+    let (mut flags, mut table, mut content) = (STORED, Vec::new(), Cow::Borrowed(b));
+    if let Some((f, t, p)) = coded.filter(|(_, t, p)| t.len() + p.len() < b.len()) {
+        (flags, table, content) = (f, t, Cow::Owned(p));
+    }
+
+    if o.lz77 {
+        let p = lz77::encode(&content);
+        if p.len() < content.len() {
+            (flags, content) = (flags | LZ77, Cow::Owned(p));
         }
+    }
 
-        let map = WordsMap::from_plain(plain);
+    let mut lengths = Vec::new();
+    if o.huffman {
+        let codes = Huffman::from(&content);
+        let (l, p) = (codes.to_bytes(), codes.encode(&content));
 
-        let mut words = split(&content);
+        if l.len() + p.len() < content.len() {
+            (flags, lengths, content) = (flags | HUFFMAN, l, Cow::Owned(p));
+        }
+    }
 
-        for (ch, word) in map.iter() {
-            words
-                .iter_mut()
-                .map(|w| {
-                    if w == ch.as_str() {
-                        *w = word.as_str().to_owned()
-                    }
-                })
-                .for_each(drop);
+    Block {
+        size: b.len() as u32,
+        flags,
+        hash: h.checksum.digest(&[&lengths, &table, &content]),
+        check: h.checksum.digest(&[b]),
+        huffman: lengths,
+        dict: table,
+        payload: content.into_owned(),
+    }
+}
+
+/// Substitutes words and phrases, gives the table and the content unless
+/// there is nothing worth a code.
+#[inline]
+pub(crate) fn words_block(
+    s: &[&[u8]],
+    a: Alphabet,
+    max_ngram: usize,
+) -> Option<(WordsMap, Vec<u8>)> {
+    let mut words = Words::from(a);
+
+    // Only whole words can be swapped for codes without merging with their
+    // neighbours, so phrases start and end with one
+    let starts = (0..s.len()).filter(|&i| is_word(s[i], a)).collect_vec();
+    for (k, &i) in starts.iter().enumerate() {
+        words.insert(s[i], false);
+        for &j in starts[k + 1..].iter().take(max_ngram.saturating_sub(1)) {
+            words.insert(&s[i..=j].concat(), true);
+        }
+    }
+    words.clear();
+
+    if words.is_empty() {
+        return None;
+    }
+    let (mut w, c) = words.into_vecs();
+
+    // Overlapping phrases and the words in them are counted more often
+    // than they get used, so entries that do not pay off after all are
+    // dropped until the rest does. Unused ones go first, then the longest:
+    // the words they covered may pay off without them. A phrase only pays
+    // for what its words would not save on their own.
+    let mut passes = 0;
+    let (m, p) = loop {
+        let m = WordsMap::from(w.clone(), c.clone());
+        let (p, uses) = substitute(s, &m, a);
+        if passes == PRUNE_PASSES {
+            break (m, p);
         }
 
-        Ok(ToSave::new(words.join("")))
+        let codes: HashMap<&[u8], &[u8]> =
+            m.iter().map(|(ch, word)| (word.as_slice(), ch.as_bytes())).collect();
+        // What a use would take without the entry itself
+        let used = |word: &[u8]| match split(word, a).as_slice() {
+            [_] => word.len(),
+            tokens => tokens.iter().map(|t| codes.get(t).map_or(t.len(), |ch| ch.len())).sum(),
+        };
+        let uses: HashMap<&[u8], usize> =
+            m.iter().zip(uses).map(|((_, word), n)| (word.as_slice(), n)).collect();
+
+        let pays = |w: &mut Word| match (codes.get(w.bytes()), uses.get(w.bytes())) {
+            (Some(ch), Some(&n)) => {
+                w.set_amount(n);
+                w.savings_over(used(w.bytes()), ch.len()) > 0
+            }
+            _ => false,
+        };
+        let rank = |w: &Word| match w.amount() {
+            0 => (true, 0),
+            _ => (false, split(w.bytes(), a).len()),
+        };
+        let failing = w.iter_mut().filter_map(|w| (!pays(w)).then(|| rank(w)));
+        let Some(worst) = failing.max() else { break (m, p) };
+
+        passes += 1;
+        w.retain_mut(|w| pays(w) || passes < PRUNE_PASSES && rank(w) < worst);
+    };
+
+    Some((m, p))
+}
+
+#[inline]
+fn unzip_block(block: Block, h: &Header, shared: Option<&WordsMap>) -> Result<Vec<u8>> {
+    if h.checksum.digest(&[&block.huffman, &block.dict, &block.payload]) != block.hash {
+        return Err(Error::ChecksumMismatch);
+    }
+    if block.flags & !(h.flags | STORED) != 0 {
+        return Err(Error::corrupted("Block coded by a stage the archive does not use."));
+    }
+
+    // Every marker and doubled byte of the transforms takes the place of a
+    // byte at most. The size comes from the archive, so it only bounds what
+    // the stages accept and is never allocated up front.
+    let mut size = block.size as usize;
+    if block.flags & CASE != 0 {
+        size *= 2
+    }
+    if block.flags & GAPS != 0 {
+        size = 2 * size + gaps::MAX_TABLE
     }
+
+    let mut payload = if block.flags & HUFFMAN != 0 {
+        Huffman::from_bytes(&block.huffman)?.decode(&block.payload)?
+    } else {
+        block.payload
+    };
+    if block.flags & LZ77 != 0 {
+        payload = lz77::decode(&payload, size)?;
+    }
+
+    let mut words = if block.flags & STORED != 0 {
+        payload
+    } else if block.flags & BPE != 0 {
+        Bpe::from_bytes(&block.dict)?.decode(&payload, size)?
+    } else if block.flags & PPM != 0 {
+        Ppm::from_bytes(&block.dict)?.decode(&payload, size)?
+    } else if block.flags & DICT != 0 {
+        let unexpected = || Error::corrupted("Unexpected block table.");
+        let m = shared.filter(|_| block.dict.is_empty()).ok_or_else(unexpected)?;
+        restore(&payload, m, h.alphabet)?
+    } else {
+        restore(&payload, &WordsMap::from_bytes(&block.dict)?, h.alphabet)?
+    };
+    if block.flags & GAPS != 0 {
+        words = gaps::decode(&words)?;
+    }
+    if block.flags & CASE != 0 {
+        words = case::unfold(&words, h.alphabet)?;
+    }
+
+    // The payload was intact, so a difference here comes from the substitution itself
+    if words.len() as u64 != block.size as u64 || h.checksum.digest(&[&words]) != block.check {
+        return Err(Error::DataMismatch);
+    }
+
+    Ok(words)
 }
 
-pub(self) mod ch {
+mod ch {
+
+    use crate::alphabet::Alphabet;
 
     #[derive(PartialEq, Eq)]
     pub enum Ch {
         Alphabetic,
         WhiteSpace,
         Other,
+        Binary, // bytes that are not valid UTF-8
     }
 
     impl Ch {
         #[inline]
-        pub fn from(c: char) -> Self {
-            if char::is_alphabetic(c) {
+        pub fn from(c: char, a: Alphabet) -> Self {
+            if char::is_alphabetic(c) || a.contains(c) {
                 Self::Alphabetic
             } else if char::is_whitespace(c) {
                 Self::WhiteSpace
@@ -145,27 +603,152 @@ pub(self) mod ch {
     }
 }
 
+/// Replaces dictionary words and phrases by their codes, returns how often
+/// every entry of `m` was used.
+///
+/// Alphabetic tokens that collide with a code are prefixed by `ESC` and every
+/// literal `ESC` is doubled, so `restore` never confuses them with codes.
+/// Entries are looked up in a single pass, the longest one that fits goes
+/// first at every place.
 #[inline]
-fn split(s: &str) -> Vec<String> {
+fn substitute(s: &[&[u8]], m: &WordsMap, a: Alphabet) -> (Vec<u8>, Vec<usize>) {
+    let entries: HashMap<&[u8], (usize, &[u8])> = m
+        .iter()
+        .enumerate()
+        .map(|(i, (ch, word))| (word.as_slice(), (i, ch.as_bytes())))
+        .collect();
+    // Lengths in tokens of the entries that start with a token, longest first
+    let mut lens: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (_, word) in m.iter() {
+        let tokens = split(word, a);
+        lens.entry(tokens[0]).or_default().push(tokens.len());
+    }
+    lens.values_mut().for_each(|l| {
+        l.sort_unstable_by_key(|&n| Reverse(n));
+        l.dedup()
+    });
+    // Tokens that need escaping are never part of an entry
+    let plain = s.iter().map(|w| !w.contains(&ESC) && !m.is_code(w)).collect_vec();
+
+    let mut r = Vec::with_capacity(s.iter().map(|w| w.len()).sum());
+    let mut uses = vec![0; m.iter().len()];
+    let mut key = Vec::new();
+
+    let mut j = 0;
+    while j < s.len() {
+        let found = lens.get(s[j]).into_iter().flatten().find_map(|&n| {
+            let span = s.get(j..j + n).filter(|_| plain[j..j + n].iter().all(|&p| p))?;
+            key.clear();
+            span.iter().for_each(|t| key.extend_from_slice(t));
+            entries.get(key.as_slice()).map(|&entry| (n, entry))
+        });
+
+        if let Some((n, (i, ch))) = found {
+            r.extend_from_slice(ch);
+            uses[i] += 1;
+            j += n;
+            continue;
+        }
+
+        let w = s[j];
+        if w.contains(&ESC) {
+            for &b in w {
+                if b == ESC {
+                    r.push(ESC)
+                }
+                r.push(b)
+            }
+        } else if !plain[j] {
+            r.push(ESC);
+            r.extend_from_slice(w);
+        } else {
+            r.extend_from_slice(w);
+        }
+        j += 1;
+    }
+
+    (r, uses)
+}
+
+/// Reverses `substitute`.
+#[inline]
+fn restore(content: &[u8], m: &WordsMap, a: Alphabet) -> Result<Vec<u8>> {
+    let codes: HashMap<&[u8], &[u8]> =
+        m.iter().map(|(ch, word)| (ch.as_bytes(), word.as_slice())).collect();
+
+    let tokens = split(content, a);
+    let mut r = Vec::with_capacity(content.len() * 2);
+    let mut literal = false;
+
+    for (i, &t) in tokens.iter().enumerate() {
+        if std::mem::take(&mut literal) {
+            r.extend_from_slice(t);
+            continue;
+        }
+        if !t.contains(&ESC) {
+            r.extend_from_slice(codes.get(t).unwrap_or(&t));
+            continue;
+        }
+
+        let mut bytes = t.iter();
+        while let Some(&b) = bytes.next() {
+            if b != ESC {
+                r.push(b);
+                continue;
+            }
+            match bytes.next() {
+                Some(&ESC) => r.push(ESC),
+                None if i + 1 < tokens.len() => literal = true,
+                _ => return Err(Error::corrupted("Dangling escape.")),
+            }
+        }
+    }
+
+    Ok(r)
+}
+
+/// Tells whether the token is a run of alphabetic characters.
+#[inline]
+pub(crate) fn is_word(t: &[u8], a: Alphabet) -> bool {
+    t.utf8_chunks()
+        .next()
+        .and_then(|c| c.valid().chars().next())
+        .is_some_and(|c| ch::Ch::from(c, a) == ch::Ch::Alphabetic)
+}
+
+/// Splits `s` into runs of alike characters, the characters of `a` count as
+/// alphabetic.
+///
+/// Only the parts that decode as UTF-8 are split by character class, every
+/// invalid run becomes a token of its own.
+#[inline]
+pub(crate) fn split(s: &[u8], a: Alphabet) -> Vec<&[u8]> {
     use ch::Ch;
 
-    let mut vec: Vec<String> = Vec::with_capacity(s.len() / 8);
-    let mut buf = String::with_capacity(15);
+    let mut vec: Vec<&[u8]> = Vec::with_capacity(s.len() / 8);
+
+    let classes = s.utf8_chunks().flat_map(|chunk| {
+        let invalid = chunk.invalid().len();
+        chunk
+            .valid()
+            .chars()
+            .map(|c| (Ch::from(c, a), c.len_utf8()))
+            .chain((invalid > 0).then_some((Ch::Binary, invalid)))
+    });
 
-    let mut chars = s.chars().peekable();
-    let mut prev = Ch::from(*chars.peek().expect("Specify non-empty file!"));
+    let (mut start, mut end) = (0, 0);
+    let mut prev = None;
 
-    for c in chars {
-        let current = Ch::from(c);
-        if current != prev && !buf.is_empty() {
-            vec.push(buf.to_owned());
-            buf.clear()
+    for (current, len) in classes {
+        if prev.as_ref() != Some(&current) && end > start {
+            vec.push(&s[start..end]);
+            start = end
         }
-        buf.push(c);
-        prev = current
+        end += len;
+        prev = Some(current)
     }
-    if !buf.is_empty() {
-        vec.push(buf.to_owned())
+    if end > start {
+        vec.push(&s[start..end])
     }
 
     vec
@@ -176,15 +759,22 @@ fn split_cases() {
     let s = "some, string.. with !some \n symbols \t, need to separate this ";
     let rus = "некая русская строка \n, с \u{2223} ,, ,as to";
 
+    fn split_str(s: &str) -> Vec<&str> {
+        split(s.as_bytes(), Alphabet::Letters)
+            .into_iter()
+            .map(|t| std::str::from_utf8(t).unwrap())
+            .collect_vec()
+    }
+
     assert_eq!(
-        split(s),
+        split_str(s),
         vec![
             "some", ",", " ", "string", "..", " ", "with", " ", "!", "some", " \n ", "symbols",
             " \t", ",", " ", "need", " ", "to", " ", "separate", " ", "this", " "
         ]
     );
     assert_eq!(
-        split(rus),
+        split_str(rus),
         vec![
             "некая",
             " ",
@@ -206,4 +796,453 @@ fn split_cases() {
             "to"
         ]
     );
+    assert_eq!(
+        split(b"ab\xff\xfecd \xe9t\xe9", Alphabet::Letters),
+        vec![&b"ab"[..], b"\xff\xfe", b"cd", b" ", b"\xe9", b"t", b"\xe9"]
+    );
+}
+
+#[test]
+fn escape_cases() {
+    use crate::indexation::words::Word;
+
+    // The last entry is what `b, c` looks like once the code `c` is escaped
+    let words = [&b"consectetur"[..], b"adipiscing", b"b, \x1bc"];
+    let m = WordsMap::from(words.map(|w| Word::new(w.to_vec())).to_vec(), vec!['a', 'b', 'c']);
+    let s = b"a b consectetur, ab\x1b adipiscing \x1ba,\x1b\x1bb\x1bc ,a\xffb consectetur, b, c";
+
+    let a = Alphabet::Letters;
+    let (content, uses) = substitute(&split(s, a), &m, a);
+    assert_eq!(uses, vec![2, 1, 0]);
+
+    assert!(content.windows(10).all(|w| w != b"adipiscing"));
+    assert_eq!(restore(&content, &m, a).unwrap(), s);
+    assert!(restore(b"\x1b", &m, a).is_err());
+    assert!(restore(b"x \x1b, y", &m, a).is_err());
+}
+
+#[cfg(test)]
+fn zip(b: &[u8], o: Options) -> Vec<u8> {
+    let mut r = Vec::new();
+    Zip::from(b, o).unwrap().proc(&mut r).unwrap();
+    r
+}
+
+#[cfg(test)]
+fn unzip(b: &[u8]) -> Result<Vec<u8>> {
+    let mut r = Vec::new();
+    Unzip::from(b).proc(&mut r).map(|_| r)
+}
+
+#[test]
+fn round_trip() {
+    let mut original = std::fs::read("short").unwrap();
+    // Latin-1 and binary garbage in the middle of the text
+    original.extend_from_slice(b"caf\xe9 na\xefve \x00\xff\xfe\x80 ");
+    original.extend(std::fs::read("short").unwrap());
+
+    let checksums = [Checksum::None, Checksum::Crc32, Checksum::Xxh64, Checksum::Sha256];
+
+    for ((block_size, checksum), huffman) in [1 << 20, 16 << 10]
+        .into_iter()
+        .cartesian_product(checksums)
+        .cartesian_product([false, true])
+    {
+        let options = Options {
+            block_size,
+            checksum,
+            huffman,
+            ..Options::default()
+        };
+        let zipped = zip(&original, options);
+        assert!(zipped.starts_with(crate::format::MAGIC));
+        assert!(zipped.len() < original.len());
+        assert_eq!(unzip(&zipped).unwrap(), original);
+    }
+}
+
+#[test]
+fn nested_round_trip() {
+    // The old section separator right in the text
+    let original = std::fs::read_to_string("short").unwrap().replace(". ", ".\u{2ffff} ");
+
+    let once = zip(original.repeat(2).as_bytes(), Options::default());
+    // An archive next to plain text, which gives the second pass something to replace
+    let mixed = [once.as_slice(), original.as_bytes()].concat();
+    let twice = zip(&mixed, Options::default());
+
+    let back = unzip(&twice).unwrap();
+    assert_eq!(back, mixed);
+    assert_eq!(unzip(&back[..once.len()]).unwrap(), original.repeat(2).as_bytes());
+
+    // Concatenated archives are not a single archive
+    assert!(unzip(&back).is_err());
+}
+
+#[test]
+fn checksum_mismatch() {
+    let original = std::fs::read("short").unwrap();
+
+    for checksum in [Checksum::Crc32, Checksum::Xxh64, Checksum::Sha256] {
+        let options = Options {
+            checksum,
+            ..Options::default()
+        };
+        let mut zipped = zip(&original, options);
+
+        // Flip a bit in the middle of the payload
+        let n = zipped.len() / 2;
+        zipped[n] ^= 1;
+        assert!(matches!(unzip(&zipped), Err(Error::ChecksumMismatch)));
+    }
+}
+
+#[test]
+fn decompressed_mismatch() {
+    let original = std::fs::read("short").unwrap();
+    let zipped = zip(&original, Options::default());
+
+    let mut r = Reader::from(zipped.as_slice());
+    let header = Header::read(&mut r).unwrap();
+    let mut block = Block::read(&mut r, &header).unwrap().unwrap();
+
+    // A consistent archive whose dictionary restores other words
+    *block.dict.last_mut().unwrap() = b'#';
+    block.hash = header.checksum.digest(&[&block.huffman, &block.dict, &block.payload]);
+
+    let mut forged = Vec::new();
+    header.write(&mut forged).unwrap();
+    block.write(&mut forged).unwrap();
+    Block::write_end(&mut forged, original.len() as u64).unwrap();
+
+    assert!(matches!(unzip(&forged), Err(Error::DataMismatch)));
+}
+
+#[test]
+fn block_size_bounds() {
+    let block_size = |block_size| Options {
+        block_size,
+        ..Options::default()
+    };
+
+    // No block at all and a size a block can not record
+    for size in [0, 1023, MAX_BLOCK_SIZE + 1] {
+        let e = Zip::from(&b"some words"[..], block_size(size)).err();
+        assert!(matches!(e, Some(Error::InvalidArgs(_))));
+    }
+    for size in [1024, MAX_BLOCK_SIZE] {
+        assert_eq!(unzip(&zip(b"some words", block_size(size))).unwrap(), b"some words");
+    }
+}
+
+#[test]
+fn empty_and_stored() {
+    let stats = |b: &[u8]| Unzip::from(b).proc(&mut Vec::new()).unwrap();
+
+    let mut empty = Vec::new();
+    let nothing = Zip::from(&b""[..], Options::default()).unwrap().proc(&mut empty).unwrap();
+    assert_eq!(nothing, Stats::default());
+    assert!(!nothing.nothing_gained());
+    assert_eq!(unzip(&empty).unwrap(), b"");
+
+    for tiny in [&b"a"[..], b" ", b"\x1b", b"\xff\xfe", b"no words repeat here"] {
+        let zipped = zip(tiny, Options::default());
+        let stats = stats(&zipped);
+        assert_eq!((stats.blocks, stats.stored), (1, 1));
+        assert!(stats.nothing_gained());
+        assert_eq!(unzip(&zipped).unwrap(), tiny);
+    }
+
+    assert!(matches!(unzip(b""), Err(Error::EmptyInput)));
+}
+
+#[test]
+fn huffman_stage() {
+    let huffman = |huffman| Options {
+        huffman,
+        ..Options::default()
+    };
+
+    let original = std::fs::read("short").unwrap().repeat(4);
+    let words = zip(&original, huffman(false));
+    assert!(zip(&original, huffman(true)).len() < words.len());
+
+    // Nothing to substitute, yet the bytes are skewed enough to code
+    let spaces = b" \n".repeat(1000);
+    let coded = zip(&spaces, huffman(true));
+    let stats = Unzip::from(coded.as_slice()).proc(&mut Vec::new()).unwrap();
+    assert_eq!((stats.blocks, stats.stored), (1, 0));
+    assert!(coded.len() < spaces.len() / 4);
+    assert_eq!(unzip(&coded).unwrap(), spaces);
+}
+
+#[test]
+fn private_use_alphabet() {
+    let alphabet = |alphabet| Options {
+        alphabet,
+        ..Options::default()
+    };
+
+    // More distinct frequent words than there are letters and pairs of them
+    let word = |i: u32| {
+        let letter = |n: u32| char::from(b'a' + (n % 26) as u8);
+        format!("{}{}{}ward ", letter(i), letter(i / 26), letter(i / 676))
+    };
+    let mut original = (0..4 * 2900).map(|i| word(i % 2900)).collect::<String>();
+    // Private use characters in the text stay what they are
+    original.push_str("\u{e000} a\u{e001}b \u{f8ff}\u{f8ff}");
+
+    let letters = zip(original.as_bytes(), alphabet(Alphabet::Letters));
+    let private = zip(original.as_bytes(), alphabet(Alphabet::PrivateUse));
+    assert!(private.len() < letters.len());
+
+    for zipped in [letters, private] {
+        assert_eq!(unzip(&zipped).unwrap(), original.as_bytes());
+    }
+}
+
+#[test]
+fn phrase_cases() {
+    let max_ngram = |max_ngram| Options {
+        max_ngram,
+        ..Options::default()
+    };
+    let entries = |zipped: &[u8]| {
+        let mut r = Reader::from(zipped);
+        let header = Header::read(&mut r).unwrap();
+        let block = Block::read(&mut r, &header).unwrap().unwrap();
+        let m = WordsMap::from_bytes(&block.dict).unwrap();
+        m.iter().map(|(_, w)| String::from_utf8(w.clone()).unwrap()).collect_vec()
+    };
+
+    let original = (0..40)
+        .map(|i| format!("Lorem ipsum dolor sit amet, consectetur adipiscing elit {i}. "))
+        .collect::<String>();
+
+    let phrases = zip(original.as_bytes(), max_ngram(3));
+    let words = zip(original.as_bytes(), max_ngram(1));
+    assert!(phrases.len() < words.len());
+    assert!(entries(&phrases).iter().any(|w| w.contains("amet, consectetur")));
+    assert!(entries(&words).iter().all(|w| !w.contains(' ')));
+
+    for zipped in [phrases, words] {
+        assert_eq!(unzip(&zipped).unwrap(), original.as_bytes());
+    }
+}
+
+#[test]
+fn fold_case_and_gaps() {
+    let text = std::fs::read_to_string("short").unwrap();
+    // With the marker and code bytes of the transforms in the text
+    let odd = text.replace(". ", ". \u{1c}\u{1}");
+    let original = [text.clone(), text.to_uppercase(), odd].concat();
+
+    let zip = |method, fold_case, gaps| {
+        let options = Options {
+            method,
+            fold_case,
+            gaps,
+            ..Options::default()
+        };
+        zip(original.as_bytes(), options)
+    };
+
+    for method in [Method::Words, Method::Bpe, Method::Ppm] {
+        let plain = zip(method, false, false).len();
+        assert!(zip(method, true, false).len() < plain);
+        assert!(zip(method, false, true).len() < plain);
+
+        for (fold_case, gaps) in [(true, false), (false, true), (true, true)] {
+            assert_eq!(unzip(&zip(method, fold_case, gaps)).unwrap(), original.as_bytes());
+        }
+    }
+}
+
+#[test]
+fn shared_dict() {
+    let text = std::fs::read_to_string("short").unwrap();
+    let sentences = text.split_inclusive(". ").collect_vec();
+    // Small documents, each too short for a table of its own
+    let docs = sentences.chunks(4).map(|c| c.concat()).collect_vec();
+
+    let mut dict = Vec::new();
+    Train::from(text.as_bytes(), Options::default()).proc(&mut dict).unwrap();
+    let dict = || Dict::from_bytes(&dict).unwrap();
+
+    let (mut own, mut shared) = (0, 0);
+    for doc in &docs {
+        own += zip(doc.as_bytes(), Options::default()).len();
+        let mut zipped = Vec::new();
+        let z = Zip::from(doc.as_bytes(), Options::default()).unwrap().with_dict(dict());
+        z.proc(&mut zipped).unwrap();
+        shared += zipped.len();
+
+        let mut back = Vec::new();
+        Unzip::from(zipped.as_slice()).with_dict(dict()).proc(&mut back).unwrap();
+        assert_eq!(back, doc.as_bytes());
+
+        // Without the dictionary, or with another one
+        assert!(matches!(unzip(&zipped), Err(Error::InvalidArgs(_))));
+        let other = Dict::train(&b"other words ".repeat(20), Alphabet::Letters, 1).unwrap();
+        let e = Unzip::from(zipped.as_slice()).with_dict(other).proc(&mut Vec::new());
+        assert!(e.is_err());
+    }
+    assert!(shared < own * 3 / 4);
+}
+
+#[test]
+fn shared_dict_transforms() {
+    let text = std::fs::read_to_string("short").unwrap();
+    let doc = text.split_inclusive(". ").take(4).collect::<String>();
+    let options = Options {
+        fold_case: true,
+        gaps: true,
+        ..Options::default()
+    };
+
+    let train = |options| {
+        let mut dict = Vec::new();
+        Train::from(text.as_bytes(), options).proc(&mut dict).unwrap();
+        Dict::from_bytes(&dict).unwrap()
+    };
+    let zip = |dict| {
+        let mut zipped = Vec::new();
+        let z = Zip::from(doc.as_bytes(), options).unwrap().with_dict(dict);
+        z.proc(&mut zipped).unwrap();
+        zipped
+    };
+
+    // Trained on the text the blocks are coded from, not on the raw one
+    let (raw, same) = (zip(train(Options::default())), zip(train(options)));
+    let mut back = Vec::new();
+    Unzip::from(same.as_slice()).with_dict(train(options)).proc(&mut back).unwrap();
+    assert_eq!(back, doc.as_bytes());
+    assert!(same.len() < raw.len());
+}
+
+#[test]
+fn threads() {
+    let original = std::fs::read("short").unwrap().repeat(8);
+
+    let zip = |threads| {
+        let options = Options {
+            block_size: 8 << 10,
+            threads,
+            ..Options::default()
+        };
+        zip(&original, options)
+    };
+
+    // The same archive whatever the threads, and whatever the batches of blocks
+    let one = zip(1);
+    for threads in [2, 3, 0] {
+        assert_eq!(zip(threads), one);
+    }
+
+    for threads in [1, 3, 0] {
+        let mut back = Vec::new();
+        let stats = Unzip::from(one.as_slice()).with_threads(threads).proc(&mut back).unwrap();
+        assert!(stats.blocks > 3);
+        assert_eq!(back, original);
+    }
+}
+
+#[test]
+fn bpe_and_ppm_methods() {
+    let original = std::fs::read("short").unwrap().repeat(2);
+    let method = |method| Options {
+        method,
+        ..Options::default()
+    };
+
+    let methods = [Method::Bpe, Method::Ppm];
+    for (method, huffman) in methods.into_iter().cartesian_product([false, true]) {
+        let options = Options {
+            method,
+            huffman,
+            ..Options::default()
+        };
+        let zipped = zip(&original, options);
+        let stats = Unzip::from(zipped.as_slice()).proc(&mut Vec::new()).unwrap();
+        assert_eq!(stats.stored, 0);
+        assert!(zipped.len() < original.len() / 2);
+        assert_eq!(unzip(&zipped).unwrap(), original);
+    }
+
+    // Context modelling codes the same tokens tighter than a word table
+    let ppm = zip(&original, method(Method::Ppm));
+    assert!(ppm.len() < zip(&original, method(Method::Words)).len());
+}
+
+#[test]
+fn lz77_pipelines() {
+    // Lines that only differ by a field, which words do not catch
+    let original = (0..500)
+        .map(|i| format!("2024-01-01T10:{:02}:00 node-7 GET /api/v1/items?page={i} 200\n", i % 60))
+        .collect::<String>();
+    let words = zip(original.as_bytes(), Options::default());
+
+    for method in [Method::Words, Method::Bpe, Method::Ppm, Method::None] {
+        let options = Options {
+            method,
+            lz77: true,
+            ..Options::default()
+        };
+        let zipped = zip(original.as_bytes(), options);
+        assert!(zipped.len() < words.len() / 2);
+
+        let header = Header::read(&mut Reader::from(zipped.as_slice())).unwrap();
+        assert_eq!(header.flags, options.stages());
+        assert_eq!(unzip(&zipped).unwrap(), original.as_bytes());
+    }
+
+    // A block may not use a stage the header does not list
+    let mut r = Reader::from(words.as_slice());
+    let header = Header::read(&mut r).unwrap();
+    let mut block = Block::read(&mut r, &header).unwrap().unwrap();
+    block.flags |= LZ77;
+    let e = unzip_block(block, &header, None).unwrap_err();
+    assert!(matches!(e, Error::Corrupted(_)));
+}
+
+#[test]
+fn forged_size() {
+    let a = Alphabet::Letters;
+    let original = b"Lorem ipsum, lorem ipsum, LOREM ipsum. ".repeat(4);
+    let text = gaps::encode(&case::fold(&original, a), a);
+    let tokens = split(&text, a);
+
+    let (bpe, bpe_seq) = Bpe::from(&tokens);
+    let (ppm, ppm_seq) = Ppm::from(&tokens);
+    let stages = [
+        (BPE, bpe.to_bytes(), Bpe::encode(&bpe_seq)),
+        (PPM, ppm.to_bytes(), Ppm::encode(&ppm_seq)),
+        (STORED | LZ77, Vec::new(), lz77::encode(&text)),
+    ];
+
+    // Valid stages that claim a block of 4 GiB, decoded without allocating
+    // it up to the end, where the size does not match
+    for (flags, dict, payload) in stages {
+        let header = Header {
+            flags: flags | CASE | GAPS,
+            checksum: Checksum::None,
+            alphabet: Alphabet::Letters,
+            dict: None,
+        };
+        let block = Block {
+            size: u32::MAX,
+            flags: flags | CASE | GAPS,
+            hash: Vec::new(),
+            check: Vec::new(),
+            huffman: Vec::new(),
+            dict,
+            payload,
+        };
+
+        let mut forged = Vec::new();
+        header.write(&mut forged).unwrap();
+        block.write(&mut forged).unwrap();
+        Block::write_end(&mut forged, u32::MAX as u64).unwrap();
+        assert!(matches!(unzip(&forged), Err(Error::DataMismatch)));
+    }
 }